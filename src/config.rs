@@ -0,0 +1,91 @@
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use elements_miniscript as miniscript;
+use miniscript::{bitcoin, elements};
+use serde::{Deserialize, Deserializer};
+
+use crate::error::Error;
+use crate::network;
+use crate::network::Network;
+use crate::rpc::Connection;
+
+/// Settings that take precedence over the values in the state file.
+///
+/// Settings are read from the config file and from the command line.
+/// They are never written back to the state file.
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, deserialize_with = "network_from_str")]
+    pub network: Option<Network>,
+    #[serde(default)]
+    pub rpc: Option<Connection>,
+    #[serde(default, with = "bitcoin::amount::serde::as_sat::opt")]
+    pub fee: Option<bitcoin::Amount>,
+    #[serde(default)]
+    pub scan_batch_size: Option<usize>,
+    #[serde(default)]
+    pub max_fee_percent: Option<u64>,
+    #[serde(default)]
+    pub scan_cache_ttl: Option<u64>,
+    #[serde(default, deserialize_with = "address_params_from_table")]
+    pub address_params: Option<&'static elements::AddressParams>,
+}
+
+impl Config {
+    /// Load the config from the given TOML file.
+    ///
+    /// A missing file is treated as an empty config.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(|e| Error::CouldNotParse(e.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Overwrite the settings of `self` with the settings of `other` that are present.
+    pub fn merge(&mut self, other: Config) {
+        if other.network.is_some() {
+            self.network = other.network;
+        }
+        if other.rpc.is_some() {
+            self.rpc = other.rpc;
+        }
+        if other.fee.is_some() {
+            self.fee = other.fee;
+        }
+        if other.scan_batch_size.is_some() {
+            self.scan_batch_size = other.scan_batch_size;
+        }
+        if other.max_fee_percent.is_some() {
+            self.max_fee_percent = other.max_fee_percent;
+        }
+        if other.scan_cache_ttl.is_some() {
+            self.scan_cache_ttl = other.scan_cache_ttl;
+        }
+        if other.address_params.is_some() {
+            self.address_params = other.address_params;
+        }
+    }
+}
+
+fn network_from_str<'de, D>(deserializer: D) -> Result<Option<Network>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Network::from_str(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn address_params_from_table<'de, D>(
+    deserializer: D,
+) -> Result<Option<&'static elements::AddressParams>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    network::deserialize_address_params(deserializer).map(Some)
+}
@@ -12,49 +12,97 @@ use miniscript::elements;
 use miniscript::{Descriptor, MiniscriptKey};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::key::UnspendableKey;
-
-pub fn simplicity_pk<Pk: MiniscriptKey + UnspendableKey>(key: Pk) -> Descriptor<Pk> {
-    let internal_key = Pk::unspendable();
+pub fn simplicity_pk<Pk: MiniscriptKey>(internal_key: Pk, key: Pk) -> Descriptor<Pk> {
     let policy = simplicity::Policy::Key(key);
     let tree = TapTree::SimplicityLeaf(Arc::new(policy));
     Descriptor::new_tr(internal_key, Some(tree)).expect("single leaf is within bounds")
 }
 
-pub fn simplicity_asm<Pk: MiniscriptKey + UnspendableKey>(cmr: simplicity::Cmr) -> Descriptor<Pk> {
-    let internal_key = Pk::unspendable();
+pub fn simplicity_asm<Pk: MiniscriptKey>(internal_key: Pk, cmr: simplicity::Cmr) -> Descriptor<Pk> {
     let policy = simplicity::Policy::Assembly(cmr);
     let tree = TapTree::SimplicityLeaf(Arc::new(policy));
     Descriptor::new_tr(internal_key, Some(tree)).expect("single leaf is within bounds")
 }
 
+fn collect_simplicity_leaves<'a, Pk: MiniscriptKey>(
+    tree: &'a TapTree<Pk>,
+    leaves: &mut Vec<&'a simplicity::Policy<Pk>>,
+) {
+    match tree {
+        TapTree::Tree(left, right) => {
+            collect_simplicity_leaves(left, leaves);
+            collect_simplicity_leaves(right, leaves);
+        }
+        TapTree::SimplicityLeaf(policy) => leaves.push(policy),
+        _ => {}
+    }
+}
+
+/// Return the Simplicity leaves of a taproot descriptor, from left to right.
+fn simplicity_leaves<Pk: MiniscriptKey>(
+    descriptor: &Descriptor<Pk>,
+) -> Vec<&simplicity::Policy<Pk>> {
+    let mut leaves = Vec::new();
+    if let Descriptor::Tr(tr) = descriptor {
+        if let Some(tree) = tr.taptree() {
+            collect_simplicity_leaves(tree, &mut leaves);
+        }
+    }
+    leaves
+}
+
+/// Return the CMRs of all Simplicity leaves of a taproot descriptor.
+pub fn get_cmrs<Pk: ToPublicKey>(descriptor: &Descriptor<Pk>) -> Vec<simplicity::Cmr> {
+    simplicity_leaves(descriptor)
+        .into_iter()
+        .map(simplicity::Policy::cmr)
+        .collect()
+}
+
+/// Return the CMR of a taproot descriptor with a single Simplicity leaf.
 pub fn get_cmr<Pk: ToPublicKey>(descriptor: &Descriptor<Pk>) -> Option<simplicity::Cmr> {
+    match get_cmrs(descriptor).as_slice() {
+        [cmr] => Some(*cmr),
+        _ => None,
+    }
+}
+
+fn leaf_script(cmr: simplicity::Cmr) -> (elements::Script, elements::taproot::LeafVersion) {
+    let script = elements::Script::from(cmr.as_ref().to_vec());
+    (script, simplicity::leaf_version())
+}
+
+/// Return the CMR of the Simplicity leaf with the given leaf hash.
+pub fn get_leaf_cmr<Pk: ToPublicKey>(
+    descriptor: &Descriptor<Pk>,
+    leaf_hash: &elements::taproot::TapLeafHash,
+) -> Option<simplicity::Cmr> {
+    get_cmrs(descriptor).into_iter().find(|cmr| {
+        let (script, version) = leaf_script(*cmr);
+        &elements::taproot::TapLeafHash::from_script(&script, version) == leaf_hash
+    })
+}
+
+pub fn get_internal_key<Pk: ToPublicKey>(descriptor: &Descriptor<Pk>) -> Option<&Pk> {
     match descriptor {
-        Descriptor::Tr(tr) => match tr.taptree() {
-            Some(TapTree::SimplicityLeaf(policy)) => Some(policy.cmr()),
-            _ => None,
-        },
+        Descriptor::Tr(tr) => Some(tr.internal_key()),
         _ => None,
     }
 }
 
+/// Return the control block of the Simplicity leaf with the given CMR.
 pub fn get_control_block<Pk: ToPublicKey>(
     descriptor: &Descriptor<Pk>,
+    cmr: simplicity::Cmr,
 ) -> Option<elements::taproot::ControlBlock> {
     match descriptor {
-        Descriptor::Tr(tr) => match tr.taptree() {
-            Some(TapTree::SimplicityLeaf(policy)) => {
-                let cmr = policy.cmr();
-                let script = elements::Script::from(cmr.as_ref().to_vec());
-                let script_ver = (script, simplicity::leaf_version());
-                let control_block = tr
-                    .spend_info()
-                    .control_block(&script_ver)
-                    .expect("Control block must exist in script map for every known leaf");
-                Some(control_block)
-            }
-            _ => None,
-        },
+        Descriptor::Tr(tr) if get_cmrs(descriptor).contains(&cmr) => {
+            let control_block = tr
+                .spend_info()
+                .control_block(&leaf_script(cmr))
+                .expect("Control block must exist in script map for every known leaf");
+            Some(control_block)
+        }
         _ => None,
     }
 }
@@ -81,22 +129,25 @@ impl AssemblySet {
         self.iter().any(|c| &c == cmr)
     }
 
-    pub fn insert(&mut self, cmr: simplicity::Cmr) -> bool {
+    /// Insert the fragment with the given CMR behind the given internal key.
+    pub fn insert(&mut self, cmr: simplicity::Cmr, internal_key: PublicKey) -> bool {
         if self.contains(&cmr) {
             false
         } else {
-            self.descriptors.push(simplicity_asm(cmr));
+            self.descriptors.push(simplicity_asm(internal_key, cmr));
             true
         }
     }
 
-    pub fn get_address(
-        &self,
-        cmr: &simplicity::Cmr,
-        params: &'static elements::AddressParams,
-    ) -> Option<elements::Address> {
-        self.get(cmr)
-            .map(|d| d.address(params).expect("taproot address"))
+    pub fn descriptors(&self) -> impl Iterator<Item = &Descriptor<PublicKey>> {
+        self.descriptors.iter()
+    }
+
+    /// Split the CMRs of all fragments into spendable ones, which have a satisfaction,
+    /// and locked ones, which don't.
+    pub fn partition_cmrs(&self) -> (Vec<simplicity::Cmr>, Vec<simplicity::Cmr>) {
+        self.iter()
+            .partition(|cmr| self.satisfactions.contains_key(cmr))
     }
 
     pub fn locked_descriptors(&self) -> impl Iterator<Item = &Descriptor<PublicKey>> {
@@ -126,6 +177,59 @@ impl AssemblySet {
         Ok(maybe_replaced)
     }
 
+    /// Put back the satisfaction that [`Self::insert_satisfaction`] replaced,
+    /// or remove the inserted satisfaction if it didn't replace any.
+    pub fn restore_satisfaction(
+        &mut self,
+        cmr: simplicity::Cmr,
+        replaced: Option<SerdeWitnessNode<simplicity::jet::Elements>>,
+    ) {
+        match replaced {
+            Some(satisfaction) => {
+                self.satisfactions.insert(cmr, satisfaction);
+            }
+            None => {
+                self.satisfactions.remove(&cmr);
+            }
+        }
+    }
+
+    pub fn get_encoded_satisfaction(
+        &self,
+        cmr: &simplicity::Cmr,
+    ) -> Option<&SerdeWitnessNode<simplicity::jet::Elements>> {
+        self.satisfactions.get(cmr)
+    }
+
+    /// Return every problem with the fragments and their satisfactions.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for descriptor in &self.descriptors {
+            if get_cmrs(descriptor).is_empty() {
+                problems.push(format!(
+                    "assembly descriptor has no Simplicity leaf: {}",
+                    descriptor
+                ));
+            }
+        }
+        for (cmr, satisfaction) in &self.satisfactions {
+            if !self.contains(cmr) {
+                problems.push(format!("satisfaction of unknown fragment: {}", cmr));
+            }
+            let program = satisfaction.as_redeem_node();
+            if program.cmr() != *cmr {
+                problems.push(format!(
+                    "satisfaction has CMR {}, but is stored for {}",
+                    program.cmr(),
+                    cmr
+                ));
+            }
+        }
+
+        problems
+    }
+
     pub fn get_satisfaction(
         &self,
         cmr: &simplicity::Cmr,
@@ -150,6 +254,10 @@ impl<J: simplicity::jet::Jet> SerdeWitnessNode<J> {
     pub fn unwrap(&self) -> Arc<simplicity::WitnessNode<J>> {
         self.0.to_witness_node()
     }
+
+    pub fn as_redeem_node(&self) -> &simplicity::RedeemNode<J> {
+        &self.0
+    }
 }
 
 impl<J: simplicity::jet::Jet> fmt::Display for SerdeWitnessNode<J> {
@@ -3,6 +3,7 @@ use std::{fmt, io};
 use elements_miniscript as miniscript;
 use miniscript::bitcoin;
 use miniscript::elements;
+use miniscript::elements::hex::ToHex;
 
 pub enum Error {
     Cli(lexopt::Error),
@@ -15,10 +16,60 @@ pub enum Error {
     Rpc(jsonrpc::Error),
     Http(jsonrpc::simple_http::Error),
     NotEnoughFunds,
-    CouldNotSatisfy,
+    CouldNotSatisfy {
+        input: usize,
+        problems: Vec<String>,
+    },
     CouldNotParse(String),
     AssemblyOutOfBounds,
+    UtxoOutOfBounds,
+    UnknownUtxo(elements::OutPoint),
     UnknownAssembly(simplicity::Cmr),
+    ScanAborted,
+    ScanInProgress,
+    UnknownScanResult(elements::Script),
+    NoWallet,
+    WalletLocked,
+    FeeTooHigh(bitcoin::Amount),
+    FeeBelowMinimum {
+        fee: bitcoin::Amount,
+        minimum: bitcoin::Amount,
+    },
+    MissingSatisfaction(simplicity::Cmr),
+    WitnessMismatch {
+        unknown: Vec<String>,
+        missing: Vec<String>,
+    },
+    UnbalancedTransaction {
+        inputs: bitcoin::Amount,
+        outputs: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    },
+    TransactionRejected(String),
+    AmountTooSmall(bitcoin::Amount),
+    ZeroFee,
+    NotInMempool(elements::Txid),
+    NoWalletOutput(elements::Txid),
+    NotRegtest,
+    InconsistentWallet(String),
+    UnknownAccount(String),
+    AccountExists(String),
+    WrongNetwork(elements::Address),
+    DataTooLarge(usize),
+    UnsupportedKey(String),
+    DerivationExhausted {
+        account: String,
+        /// Number of unused indices that are left
+        remaining: u32,
+    },
+    InputRequired(String),
+    FeeRateTooLow {
+        /// Fee rate in sat/vB that the package would have
+        fee_rate: f64,
+        /// Fee rate in sat/vB of the parent alone
+        parent_fee_rate: f64,
+    },
+    WrongPassphrase,
 }
 
 impl Error {
@@ -32,6 +83,106 @@ impl Error {
     pub fn unknown_command(command: &str) -> Self {
         lexopt::Error::UnexpectedOption(command.into()).into()
     }
+
+    /// Return the name of the error for machine-readable output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Cli(..) => "cli",
+            Error::Simplicity(..) => "simplicity",
+            Error::HumanEncoding(..) => "human_encoding",
+            Error::Miniscript(..) => "miniscript",
+            Error::Json(..) => "json",
+            Error::IO(..) => "io",
+            Error::Bip32(..) => "bip32",
+            Error::Rpc(..) => "rpc",
+            Error::Http(..) => "http",
+            Error::NotEnoughFunds => "not_enough_funds",
+            Error::CouldNotSatisfy { .. } => "could_not_satisfy",
+            Error::CouldNotParse(..) => "could_not_parse",
+            Error::AssemblyOutOfBounds => "assembly_out_of_bounds",
+            Error::UtxoOutOfBounds => "utxo_out_of_bounds",
+            Error::UnknownUtxo(..) => "unknown_utxo",
+            Error::UnknownAssembly(..) => "unknown_assembly",
+            Error::ScanAborted => "scan_aborted",
+            Error::ScanInProgress => "scan_in_progress",
+            Error::UnknownScanResult(..) => "unknown_scan_result",
+            Error::NoWallet => "no_wallet",
+            Error::WalletLocked => "wallet_locked",
+            Error::FeeTooHigh(..) => "fee_too_high",
+            Error::FeeBelowMinimum { .. } => "fee_below_minimum",
+            Error::MissingSatisfaction(..) => "missing_satisfaction",
+            Error::WitnessMismatch { .. } => "witness_mismatch",
+            Error::UnbalancedTransaction { .. } => "unbalanced_transaction",
+            Error::TransactionRejected(..) => "transaction_rejected",
+            Error::AmountTooSmall(..) => "amount_too_small",
+            Error::ZeroFee => "zero_fee",
+            Error::NotInMempool(..) => "not_in_mempool",
+            Error::NoWalletOutput(..) => "no_wallet_output",
+            Error::NotRegtest => "not_regtest",
+            Error::InconsistentWallet(..) => "inconsistent_wallet",
+            Error::UnknownAccount(..) => "unknown_account",
+            Error::AccountExists(..) => "account_exists",
+            Error::WrongNetwork(..) => "wrong_network",
+            Error::DataTooLarge(..) => "data_too_large",
+            Error::UnsupportedKey(..) => "unsupported_key",
+            Error::DerivationExhausted { .. } => "derivation_exhausted",
+            Error::InputRequired(..) => "input_required",
+            Error::FeeRateTooLow { .. } => "fee_rate_too_low",
+            Error::WrongPassphrase => "wrong_passphrase",
+        }
+    }
+
+    /// Return the exit code of the process for this error.
+    ///
+    /// Every error has its own code. Codes are grouped by cause:
+    /// 2–9 invalid input, 10–19 wallet file, 20–29 programs and keys,
+    /// 30–39 node, 40–49 spending, 50–59 interaction.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Cli(..) => 2,
+            Error::CouldNotParse(..) => 3,
+            Error::AssemblyOutOfBounds => 4,
+            Error::UtxoOutOfBounds => 5,
+            Error::WitnessMismatch { .. } => 6,
+            Error::NotRegtest => 7,
+            Error::WrongNetwork(..) => 8,
+            Error::UnknownUtxo(..) => 9,
+            Error::NoWallet => 10,
+            Error::IO(..) => 11,
+            Error::Json(..) => 12,
+            Error::InconsistentWallet(..) => 13,
+            Error::UnknownAccount(..) => 14,
+            Error::AccountExists(..) => 15,
+            Error::UnsupportedKey(..) => 16,
+            Error::DerivationExhausted { .. } => 17,
+            Error::WalletLocked => 18,
+            Error::WrongPassphrase => 19,
+            Error::Simplicity(..) => 20,
+            Error::HumanEncoding(..) => 21,
+            Error::Miniscript(..) => 22,
+            Error::Bip32(..) => 23,
+            Error::UnknownAssembly(..) => 24,
+            Error::MissingSatisfaction(..) => 25,
+            Error::CouldNotSatisfy { .. } => 26,
+            Error::Rpc(..) => 30,
+            Error::Http(..) => 31,
+            Error::ScanAborted => 32,
+            Error::ScanInProgress => 33,
+            Error::UnknownScanResult(..) => 34,
+            Error::NotInMempool(..) => 35,
+            Error::NotEnoughFunds => 40,
+            Error::FeeTooHigh(..) => 41,
+            Error::UnbalancedTransaction { .. } => 42,
+            Error::TransactionRejected(..) => 43,
+            Error::DataTooLarge(..) => 44,
+            Error::NoWalletOutput(..) => 45,
+            Error::AmountTooSmall(..) => 46,
+            Error::ZeroFee => 47,
+            Error::FeeBelowMinimum { .. } => 48,
+            Error::FeeRateTooLow { .. } => 49,
+            Error::InputRequired(..) => 50,
+        }
+    }
 }
 
 impl fmt::Debug for Error {
@@ -53,12 +204,140 @@ impl fmt::Display for Error {
             Error::Rpc(error) => write!(f, "{}", error),
             Error::Http(error) => write!(f, "{}", error),
             Error::NotEnoughFunds => write!(f, "Not enough funds"),
-            Error::CouldNotSatisfy => write!(f, "Could not satisfy"),
+            Error::CouldNotSatisfy { input, problems } => write!(
+                f,
+                "Could not satisfy input {}: {}",
+                input,
+                problems.join("; ")
+            ),
             Error::CouldNotParse(error) => write!(f, "Could not parse: {}", error),
             Error::AssemblyOutOfBounds => write!(f, "Assembly fragment is out of bounds"),
+            Error::UtxoOutOfBounds => write!(f, "UTXO is out of bounds"),
+            Error::UnknownUtxo(outpoint) => {
+                write!(f, "UTXO is not a spendable coin of the wallet: {}", outpoint)
+            }
             Error::UnknownAssembly(cmr) => {
                 write!(f, "Unknown assembly fragment (not imported): {}", cmr)
             }
+            Error::ScanAborted => write!(f, "UTXO set scan was aborted before it completed"),
+            Error::ScanInProgress => write!(
+                f,
+                "Another UTXO set scan is still in progress, try again once it finished or cancel it with `simpiwallet abortscan`"
+            ),
+            Error::UnknownScanResult(script) => write!(
+                f,
+                "Node returned an output with a script that was not queried for: {}",
+                script.as_bytes().to_hex()
+            ),
+            Error::NoWallet => write!(f, "No wallet found — run `simpiwallet new` first"),
+            Error::WalletLocked => write!(
+                f,
+                "Another simpiwallet command is using this wallet — try again when it is done"
+            ),
+            Error::WrongPassphrase => {
+                write!(f, "Wrong passphrase, or the backup file is corrupt")
+            }
+            Error::InconsistentWallet(reason) => {
+                write!(f, "State file is inconsistent: {}", reason)
+            }
+            Error::UnknownAccount(name) => write!(f, "Unknown account: {}", name),
+            Error::AccountExists(name) => write!(f, "Account already exists: {}", name),
+            Error::FeeTooHigh(fee) => write!(
+                f,
+                "Fee of {} is too high for the amount sent (use --force to send anyway)",
+                fee
+            ),
+            Error::MissingSatisfaction(cmr) => write!(
+                f,
+                "Assembly fragment has no satisfaction (run `simpiwallet satisfyprogram` first): {}",
+                cmr
+            ),
+            Error::WitnessMismatch { unknown, missing } => {
+                write!(f, "Witness values do not match the program")?;
+                if !unknown.is_empty() {
+                    write!(f, "\nUnknown witnesses: {}", unknown.join(", "))?;
+                }
+                if !missing.is_empty() {
+                    write!(f, "\nMissing witnesses: {}", missing.join(", "))?;
+                }
+                Ok(())
+            }
+            Error::UnbalancedTransaction {
+                inputs,
+                outputs,
+                fee,
+            } => write!(
+                f,
+                "Transaction is unbalanced: inputs of {} do not pay for outputs of {} plus fee of {}",
+                inputs, outputs, fee
+            ),
+            Error::NotRegtest => write!(f, "This command only works on regtest"),
+            Error::UnsupportedKey(reason) => write!(f, "Unsupported secret key: {}", reason),
+            Error::DerivationExhausted { account, remaining } => {
+                match remaining {
+                    0 => write!(
+                        f,
+                        "Account {} has used all non-hardened derivation indices",
+                        account
+                    )?,
+                    _ => write!(
+                        f,
+                        "Account {} has only {} unused derivation indices left",
+                        account, remaining
+                    )?,
+                }
+                write!(f, " (create a new account with `simpiwallet newaccount NAME`)")
+            }
+            Error::DataTooLarge(size) => write!(
+                f,
+                "Data of {} bytes is too large for an OP_RETURN output (maximum {} bytes)",
+                size,
+                crate::spend::MAX_DATA_SIZE
+            ),
+            Error::WrongNetwork(address) => {
+                write!(f, "Address is for a different network: {}", address)
+            }
+            Error::AmountTooSmall(amount) => write!(
+                f,
+                "Amount of {} is below the dust limit of {}",
+                amount,
+                crate::spend::DUST_LIMIT
+            ),
+            Error::FeeRateTooLow {
+                fee_rate,
+                parent_fee_rate,
+            } => write!(
+                f,
+                "Fee rate of {:.1} sat/vB would not speed up a parent that already pays {:.1} sat/vB (pass a higher --fee-rate)",
+                fee_rate, parent_fee_rate
+            ),
+            Error::FeeBelowMinimum { fee, minimum } => write!(
+                f,
+                "Fee of {} is below the minimum of {} that the node accepts for this transaction",
+                fee, minimum
+            ),
+            Error::InputRequired(question) => write!(
+                f,
+                "\"{}\" needs an answer, but prompts are disabled by --yes, CI or SIMPIWALLET_NONINTERACTIVE",
+                question
+            ),
+            Error::ZeroFee => write!(
+                f,
+                "Fee is zero, so the node would not relay the transaction (use --force to send anyway)"
+            ),
+            Error::NotInMempool(txid) => {
+                write!(f, "Transaction is not in the mempool of the node: {}", txid)
+            }
+            Error::NoWalletOutput(txid) => write!(
+                f,
+                "Transaction has no spendable bitcoin output of the wallet: {}",
+                txid
+            ),
+            Error::TransactionRejected(reason) => write!(
+                f,
+                "Node would reject the transaction (use --no-check to broadcast anyway): {}",
+                reason
+            ),
         }
     }
 }
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 
 use elements::bitcoin;
+use elements::hashes::{hmac, sha256, sha512, Hash, HashEngine};
 use elements::secp256k1_zkp;
 use elements::secp256k1_zkp::rand::RngCore;
 use elements_miniscript as miniscript;
@@ -10,45 +11,118 @@ use miniscript::descriptor::{
 };
 use miniscript::elements;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 const UNSPENDABLE_PUBLIC_KEY: [u8; 32] = [
     0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
     0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
 ];
 
-pub trait UnspendableKey {
-    fn unspendable() -> Self;
+pub trait UnspendableKey: Sized {
+    /// Convert an x-only key without known discrete logarithm.
+    fn from_unspendable(key: bitcoin::key::XOnlyPublicKey) -> Self;
+
+    /// Return the NUMS point H from BIP 341.
+    fn unspendable() -> Self {
+        let key = bitcoin::key::XOnlyPublicKey::from_slice(&UNSPENDABLE_PUBLIC_KEY)
+            .expect("unspendable pubkey is valid");
+        Self::from_unspendable(key)
+    }
+
+    /// Return the point H + rG for the NUMS point H and the given scalar r.
+    ///
+    /// Anyone who knows r can verify that the key is unspendable,
+    /// but to everyone else it looks like a regular public key (see BIP 341).
+    fn unspendable_with(r: &secp256k1_zkp::SecretKey) -> Self {
+        let nums =
+            bitcoin::key::XOnlyPublicKey::unspendable().public_key(secp256k1_zkp::Parity::Even);
+        let key = nums
+            .add_exp_tweak(secp256k1_zkp::SECP256K1, &secp256k1_zkp::Scalar::from(*r))
+            .expect("tweak is a random scalar")
+            .x_only_public_key()
+            .0;
+        Self::from_unspendable(key)
+    }
 }
 
 impl UnspendableKey for bitcoin::key::XOnlyPublicKey {
-    fn unspendable() -> Self {
-        bitcoin::key::XOnlyPublicKey::from_slice(&UNSPENDABLE_PUBLIC_KEY)
-            .expect("unspendable pubkey is valid")
+    fn from_unspendable(key: bitcoin::key::XOnlyPublicKey) -> Self {
+        key
     }
 }
 
 impl UnspendableKey for bitcoin::key::PublicKey {
-    fn unspendable() -> Self {
-        bitcoin::key::XOnlyPublicKey::unspendable()
-            .to_public_key()
-            .to_public_key()
+    fn from_unspendable(key: bitcoin::key::XOnlyPublicKey) -> Self {
+        key.to_public_key()
     }
 }
 
 impl UnspendableKey for miniscript::DescriptorPublicKey {
-    fn unspendable() -> Self {
+    fn from_unspendable(key: bitcoin::key::XOnlyPublicKey) -> Self {
         Self::Single(miniscript::descriptor::SinglePub {
             origin: None,
-            key: miniscript::descriptor::SinglePubKey::XOnly(
-                bitcoin::key::XOnlyPublicKey::unspendable(),
-            ),
+            key: miniscript::descriptor::SinglePubKey::XOnly(key),
         })
     }
 }
 
+/// Generate a random scalar for [`UnspendableKey::unspendable_with`].
+pub fn random_unspendable_secret() -> secp256k1_zkp::SecretKey {
+    secp256k1_zkp::SecretKey::new(&mut secp256k1_zkp::rand::rngs::OsRng)
+}
+
+/// Derive the master blinding key from the given seed, as in SLIP 77.
+pub fn master_blinding_key(seed: &[u8]) -> secp256k1_zkp::SecretKey {
+    // SLIP 21 root node, then the child node with label "SLIP-0077"
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(b"Symmetric key seed");
+    engine.input(seed);
+    let root = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+
+    let mut engine = hmac::HmacEngine::<sha512::Hash>::new(&root[..32]);
+    engine.input(b"\x00SLIP-0077");
+    let node = hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array();
+
+    secp256k1_zkp::SecretKey::from_slice(&node[32..]).expect("hash is a valid secret key")
+}
+
+/// Derive the blinding key of the given script pubkey from the master blinding key, as in SLIP 77.
+pub fn blinding_private_key(
+    master_blinding_key: &secp256k1_zkp::SecretKey,
+    script_pubkey: &elements::Script,
+) -> secp256k1_zkp::SecretKey {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&master_blinding_key.secret_bytes());
+    engine.input(script_pubkey.as_bytes());
+    let key = hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array();
+
+    secp256k1_zkp::SecretKey::from_slice(&key).expect("hash is a valid secret key")
+}
+
+/// Descriptor secret key that overwrites its private key when it is dropped.
+///
+/// This is best effort and no guarantee: the private key is `Copy`,
+/// so copies made along the way are not overwritten,
+/// and neither are the key pairs that [`crate::state::State`] derives from it.
 #[derive(Clone, Debug)]
 pub struct DescriptorSecretKey(pub MSDescriptorSecretKey);
 
+impl Zeroize for DescriptorSecretKey {
+    fn zeroize(&mut self) {
+        match &mut self.0 {
+            MSDescriptorSecretKey::Single(single) => single.key.inner.non_secure_erase(),
+            MSDescriptorSecretKey::XPrv(xpriv) => xpriv.xkey.private_key.non_secure_erase(),
+            MSDescriptorSecretKey::MultiXPrv(xpriv) => xpriv.xkey.private_key.non_secure_erase(),
+        }
+    }
+}
+
+impl Drop for DescriptorSecretKey {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for DescriptorSecretKey {}
+
 impl Serialize for DescriptorSecretKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -58,6 +132,16 @@ impl Serialize for DescriptorSecretKey {
     }
 }
 
+impl FromStr for DescriptorSecretKey {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let descriptor_secret_key = MSDescriptorSecretKey::from_str(s)
+            .map_err(|e| crate::Error::CouldNotParse(e.to_string()))?;
+        Ok(DescriptorSecretKey(descriptor_secret_key))
+    }
+}
+
 impl<'de> Deserialize<'de> for DescriptorSecretKey {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -89,17 +173,30 @@ impl DescriptorSecretKey {
         Ok(Self(descriptor_xpriv))
     }
 
-    pub fn at_derivation_index(self, index: u32) -> Result<Self, ConversionError> {
-        match self.0 {
-            MSDescriptorSecretKey::Single(..) => Ok(self),
+    /// Derive the SLIP 77 master blinding key from this key.
+    ///
+    /// The wallet doesn't keep the seed of its key, so the private key takes the place of the seed.
+    /// Restoring the key restores the master blinding key.
+    pub fn master_blinding_key(&self) -> secp256k1_zkp::SecretKey {
+        let secret_key = match &self.0 {
+            MSDescriptorSecretKey::Single(single) => single.key.inner,
+            MSDescriptorSecretKey::XPrv(xpriv) => xpriv.xkey.private_key,
+            MSDescriptorSecretKey::MultiXPrv(xpriv) => xpriv.xkey.private_key,
+        };
+        master_blinding_key(&secret_key.secret_bytes())
+    }
+
+    pub fn at_derivation_index(&self, index: u32) -> Result<Self, ConversionError> {
+        match &self.0 {
+            MSDescriptorSecretKey::Single(..) => Ok(self.clone()),
             MSDescriptorSecretKey::XPrv(xpriv) => {
                 let derivation_path = match xpriv.wildcard {
-                    Wildcard::None => xpriv.derivation_path,
-                    Wildcard::Unhardened => xpriv.derivation_path.into_child(
+                    Wildcard::None => xpriv.derivation_path.clone(),
+                    Wildcard::Unhardened => xpriv.derivation_path.child(
                         bitcoin::bip32::ChildNumber::from_normal_idx(index)
                             .map_err(|_| ConversionError::HardenedChild)?,
                     ),
-                    Wildcard::Hardened => xpriv.derivation_path.into_child(
+                    Wildcard::Hardened => xpriv.derivation_path.child(
                         bitcoin::bip32::ChildNumber::from_hardened_idx(index)
                             .map_err(|_| ConversionError::HardenedChild)?,
                     ),
@@ -117,21 +214,57 @@ impl DescriptorSecretKey {
         }
     }
 
-    pub fn to_private_key(&self) -> bitcoin::PrivateKey {
+    /// Return the private key at the derivation path.
+    ///
+    /// Multi-path keys have no single private key, so they are rejected.
+    pub fn to_private_key(&self) -> Result<bitcoin::PrivateKey, ConversionError> {
         match &self.0 {
-            MSDescriptorSecretKey::Single(single) => single.key,
-            MSDescriptorSecretKey::XPrv(xpriv) => xpriv
+            MSDescriptorSecretKey::Single(single) => Ok(single.key),
+            MSDescriptorSecretKey::XPrv(xpriv) => Ok(xpriv
                 .xkey
                 .derive_priv(secp256k1_zkp::SECP256K1, &xpriv.derivation_path)
                 .expect("never fails")
-                .to_priv(),
-            MSDescriptorSecretKey::MultiXPrv(..) => {
-                unimplemented!("no support for multi-path xprivs")
-            }
+                .to_priv()),
+            MSDescriptorSecretKey::MultiXPrv(..) => Err(ConversionError::MultiKey),
+        }
+    }
+
+    /// Return whether the key has multiple derivation paths, which the wallet doesn't support.
+    pub fn is_multipath(&self) -> bool {
+        matches!(self.0, MSDescriptorSecretKey::MultiXPrv(..))
+    }
+
+    /// Check that the wallet can derive a chain of addresses from the key.
+    ///
+    /// This requires an xpriv with an unhardened wildcard at the end of its derivation path.
+    pub fn check_derivable(&self) -> Result<(), crate::Error> {
+        match &self.0 {
+            MSDescriptorSecretKey::XPrv(xpriv) => match xpriv.wildcard {
+                Wildcard::Unhardened => Ok(()),
+                // Addresses are derived from the xpub, which has no hardened children
+                Wildcard::Hardened => Err(crate::Error::UnsupportedKey(
+                    "hardened wildcards such as /*' are not supported, use /*".to_string(),
+                )),
+                Wildcard::None => Err(crate::Error::UnsupportedKey(
+                    "xpriv needs a wildcard such as /* at the end".to_string(),
+                )),
+            },
+            MSDescriptorSecretKey::Single(..) => Err(crate::Error::UnsupportedKey(
+                "single keys cannot derive addresses, use an xpriv".to_string(),
+            )),
+            MSDescriptorSecretKey::MultiXPrv(..) => Err(crate::Error::UnsupportedKey(
+                "multi-path xprivs are not supported".to_string(),
+            )),
         }
     }
 }
 
+/// Translator that replaces each public key with odd y-coordinate by its negation.
+///
+/// Taproot only uses the x-coordinate of keys, which is the same for P and -P.
+/// The wallet signs for -P with the negated secret key,
+/// so [`crate::state::State::get_all_keypairs`] contains key pairs for both P and -P.
+/// If the two disagreed, coins would be sent to keys that the wallet cannot sign for.
 pub struct ToEvenY;
 
 impl miniscript::Translator<bitcoin::PublicKey, bitcoin::PublicKey, ()> for ToEvenY {
@@ -169,7 +302,8 @@ mod tests {
                 .clone()
                 .at_derivation_index(index)
                 .expect("valid child index")
-                .to_private_key();
+                .to_private_key()
+                .expect("single-path key");
             let public_key_from_private_key = private_key.public_key(secp256k1_zkp::SECP256K1);
             let public_key = parent_xpub
                 .clone()
@@ -179,4 +313,66 @@ mod tests {
             assert_eq!(public_key_from_private_key, public_key);
         }
     }
+
+    #[test]
+    fn check_derivable_wildcards() {
+        let mut xpriv = DescriptorSecretKey::from_seed(&[0; 32]).expect("const");
+        assert!(xpriv.check_derivable().is_ok());
+
+        for wildcard in [Wildcard::Hardened, Wildcard::None] {
+            if let MSDescriptorSecretKey::XPrv(inner) = &mut xpriv.0 {
+                inner.wildcard = wildcard;
+            }
+            assert!(xpriv.check_derivable().is_err());
+        }
+    }
+
+    /// Return secret keys whose public keys have even and odd y-coordinates.
+    fn secret_keys_by_parity() -> (secp256k1_zkp::SecretKey, secp256k1_zkp::SecretKey) {
+        let mut even = None;
+        let mut odd = None;
+
+        for byte in 1.. {
+            let sk = secp256k1_zkp::SecretKey::from_slice(&[byte; 32]).expect("const");
+            match sk.x_only_public_key(secp256k1_zkp::SECP256K1).1 {
+                secp256k1_zkp::Parity::Even => even = even.or(Some(sk)),
+                secp256k1_zkp::Parity::Odd => odd = odd.or(Some(sk)),
+            }
+            if let (Some(even), Some(odd)) = (even, odd) {
+                return (even, odd);
+            }
+        }
+        unreachable!("half of all keys have odd y-coordinate")
+    }
+
+    #[test]
+    fn to_even_y_keeps_even_keys() {
+        let (sk, _) = secret_keys_by_parity();
+        let pk = bitcoin::PublicKey::new(sk.public_key(secp256k1_zkp::SECP256K1));
+        let translated = miniscript::Translator::pk(&mut ToEvenY, &pk).expect("infallible");
+        assert_eq!(pk, translated);
+    }
+
+    #[test]
+    fn to_even_y_negates_odd_keys() {
+        let (_, sk) = secret_keys_by_parity();
+        let pk = bitcoin::PublicKey::new(sk.public_key(secp256k1_zkp::SECP256K1));
+        let translated = miniscript::Translator::pk(&mut ToEvenY, &pk).expect("infallible");
+
+        assert_ne!(pk, translated);
+        assert_eq!(
+            secp256k1_zkp::Parity::Even,
+            translated.inner.x_only_public_key().1
+        );
+        assert_eq!(
+            pk.inner.x_only_public_key().0,
+            translated.inner.x_only_public_key().0
+        );
+        // The wallet signs for the negated key with the negated secret key
+        let negated_sk = sk.negate();
+        assert_eq!(
+            negated_sk.public_key(secp256k1_zkp::SECP256K1),
+            translated.inner
+        );
+    }
 }
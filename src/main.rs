@@ -1,154 +1,937 @@
+mod config;
 mod descriptor;
 mod error;
 mod key;
 mod network;
+mod output;
 mod parse;
+mod program;
 mod rpc;
 mod spend;
 mod state;
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
 
-use elements::hex::FromHex;
 use elements_miniscript as miniscript;
 use miniscript::{bitcoin, elements};
 use simplicity::{human_encoding, Value};
 
+use crate::config::Config;
+use crate::descriptor::SerdeWitnessNode;
 use crate::error::Error;
 use crate::key::DescriptorSecretKey;
 use crate::network::Network;
+use crate::output::{Balance, ConfigInfo, Info, NodeInfo, Output, ProgramInfo, SatisfactionResult};
 use crate::parse::Choice;
-use crate::spend::Payment;
-use crate::state::State;
+use crate::spend::{SendAmount, SendOptions, Spend, Verification};
+use crate::state::{AddressInfo, State};
 
 pub enum Command {
-    New,
-    GetNewAddress,
-    GetBalance,
-    SendToAddress { send_to: Payment },
-    SetFee { fee: bitcoin::Amount },
-    SetRpc { rpc: rpc::Connection },
-    SetNetwork { network: Network },
-    ImportProgram { program: PathBuf },
-    SatisfyProgram { program: PathBuf, witness: PathBuf },
+    New {
+        random_internal_key: bool,
+    },
+    Backup {
+        path: PathBuf,
+    },
+    Import {
+        path: PathBuf,
+    },
+    ImportKey {
+        random_internal_key: bool,
+    },
+    GetNewAddress {
+        count: Option<u32>,
+        skip_used: bool,
+    },
+    GetBalance {
+        from_height: Option<u64>,
+        since_last_scan: bool,
+        by_address: bool,
+    },
+    ListUnspent {
+        order: spend::UtxoOrder,
+        offset: usize,
+        limit: Option<usize>,
+    },
+    SendToAddress {
+        recipient: spend::Recipient,
+        amount: SendAmount,
+        options: SendOptions,
+        yes: bool,
+    },
+    PlanSend {
+        recipient: spend::Recipient,
+        amount: SendAmount,
+        options: SendOptions,
+    },
+    SendFromFile {
+        path: PathBuf,
+        options: SendOptions,
+        yes: bool,
+    },
+    SpeedUp {
+        txid: elements::Txid,
+        fee_rate: Option<f64>,
+        options: SendOptions,
+        yes: bool,
+    },
+    Consolidate {
+        max_inputs: usize,
+        options: SendOptions,
+        yes: bool,
+    },
+    SetFee {
+        fee: bitcoin::Amount,
+    },
+    SetRpc {
+        rpc: rpc::Connection,
+        force: bool,
+    },
+    SetNetwork {
+        network: Network,
+    },
+    RefreshChainParams,
+    Info,
+    GetConfig,
+    CheckWallet,
+    AbortScan,
+    NewAccount {
+        name: String,
+    },
+    SetAccount {
+        name: String,
+    },
+    IsMine {
+        address: elements::Address,
+    },
+    DeriveAddress {
+        index: u32,
+    },
+    SignRawTransaction {
+        tx: elements::Transaction,
+        prevouts: Vec<spend::Prevout>,
+    },
+    IssueAsset {
+        amount: u64,
+        token_amount: u64,
+        options: SendOptions,
+        yes: bool,
+    },
+    Fund {
+        amount: bitcoin::Amount,
+        blocks: u32,
+    },
+    ForgetUtxo {
+        outpoint: elements::OutPoint,
+        undo: bool,
+    },
+    ImportProgram {
+        program: PathBuf,
+        verbose: bool,
+    },
+    ImportCmr {
+        cmr: simplicity::Cmr,
+    },
+    SatisfyProgram {
+        /// Paths of programs and their witness data
+        pairs: Vec<(PathBuf, PathBuf)>,
+        /// Keep a new satisfaction only if a trial spend of the fragment succeeds
+        verify: bool,
+        /// Lock time and sequence of the trial spend
+        options: SendOptions,
+    },
+    DecodeSatisfaction {
+        source: SatisfactionSource,
+    },
+    SpendAssembly {
+        cmr: simplicity::Cmr,
+        address: elements::Address,
+        options: SendOptions,
+        yes: bool,
+    },
 }
 
-fn main() -> Result<(), Error> {
-    let command = parse::command()?;
+pub enum SatisfactionSource {
+    /// Satisfaction stored in the wallet for the fragment with the given CMR
+    Stored(simplicity::Cmr),
+    /// Satisfaction given in base64 encoding
+    Encoded(SerdeWitnessNode<simplicity::jet::Elements>),
+}
+
+pub struct Options {
+    /// Path to the state file
+    pub wallet: PathBuf,
+    /// Path to the config file
+    pub config: PathBuf,
+    /// Settings from the command line, which take precedence over the config file
+    pub overrides: Config,
+    /// Print the result as JSON
+    pub json: bool,
+    /// Print RPC requests and responses to stderr
+    pub debug_rpc: bool,
+    /// Print amounts in satoshi instead of L-BTC
+    pub sat: bool,
+    /// Sign with deterministic nonces
+    pub deterministic_nonces: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let wallet = std::env::var_os("WALLET_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("state.json"));
+
+        Self {
+            wallet,
+            config: PathBuf::from("simpiwallet.toml"),
+            overrides: Config::default(),
+            json: false,
+            debug_rpc: false,
+            sat: false,
+            deterministic_nonces: false,
+        }
+    }
+}
+
+fn load_state(wallet: &Path, config: &Config) -> Result<State, Error> {
+    let mut state = State::load(wallet)?;
+    state.set_overrides(config.clone());
+    Ok(state)
+}
+
+/// Print the summary of the transaction and broadcast it if the user agrees.
+///
+/// The returned output has no txid if the user declined.
+fn confirm_and_broadcast(
+    state: &mut State,
+    spend: Spend,
+    options: &SendOptions,
+    yes: bool,
+) -> Result<Output, Error> {
+    let (hex, confirmed) = confirm(state.network(), &spend, &spend.tx, options, yes)?;
+    let txid = if confirmed {
+        Some(spend::broadcast(state, &spend)?)
+    } else {
+        None
+    };
+
+    Ok(Output::Spend {
+        spend,
+        txid,
+        hex: options.print_hex.then_some(hex),
+    })
+}
+
+/// Print the summary of a signed transaction and ask the user to broadcast it.
+///
+/// On mainnet, the user has to agree even with `--yes`,
+/// unless the environment variable `SIMPIWALLET_MAINNET=1` is set.
+///
+/// Return the transaction in hex and whether the user agreed.
+fn confirm<S: std::fmt::Display>(
+    network: Network,
+    summary: &S,
+    tx: &elements::Transaction,
+    options: &SendOptions,
+    yes: bool,
+) -> Result<(String, bool), Error> {
+    eprintln!("{}\n", summary);
+
+    let hex = rpc::tx_hex(tx);
+    // Save the transaction before broadcasting, so it can be broadcast again if the node drops it
+    if let Some(path) = &options.save_hex {
+        std::fs::write(path, &hex)?;
+    }
+
+    let confirmed = yes || parse::ask("Broadcast transaction? y/n: ", true)?;
+    if confirmed
+        && network.is_mainnet()
+        && std::env::var("SIMPIWALLET_MAINNET").as_deref() != Ok("1")
+    {
+        let mainnet = parse::prompt::<Choice>(
+            "This spends real coins on Liquid mainnet. Are you sure? y/n: ",
+        )?;
+        return Ok((hex, mainnet.into()));
+    }
+    Ok((hex, confirmed))
+}
+
+/// Insert the satisfaction of the given program with the given witness data.
+///
+/// With `verify`, keep the satisfaction only if a trial spend doesn't fail,
+/// see [`spend::check_assembly_spend`].
+///
+/// Return the CMR of the program, the satisfaction that was replaced, if any,
+/// and the outcome of the trial spend.
+fn satisfy_program(
+    state: &mut State,
+    program: &Path,
+    witness: &Path,
+    verify: Option<&SendOptions>,
+) -> Result<
+    (
+        simplicity::Cmr,
+        Option<SerdeWitnessNode<simplicity::jet::Elements>>,
+        Option<Verification>,
+    ),
+    Error,
+> {
+    let file = read_input(program)?;
+    let forest = human_encoding::Forest::<simplicity::jet::Elements>::parse(&file)?;
+    let cmr = forest.roots()["main"].cmr();
+
+    if !state.assembly().contains(&cmr) {
+        return Err(Error::UnknownAssembly(cmr));
+    }
+
+    let file = read_input(witness)?;
+    let name_to_witness: HashMap<String, program::WitnessValue> = serde_json::from_str(&file)?;
+    let name_to_value = name_to_witness
+        .into_iter()
+        .map(|(name, witness)| {
+            witness
+                .to_value()
+                .map(|value| (Arc::<str>::from(name), value))
+        })
+        .collect::<Result<HashMap<Arc<str>, Arc<Value>>, Error>>()?;
+    program::check_witness_names(&forest.roots()["main"], &name_to_value)?;
+
+    let program = forest.to_witness_node(&name_to_value)?;
+    let replaced = state.assembly_mut().insert_satisfaction(&program)?;
+    let verification = match verify {
+        Some(options) => match spend::check_assembly_spend(state, &cmr, options) {
+            Ok(verification) => Some(verification),
+            Err(error) => {
+                state.assembly_mut().restore_satisfaction(cmr, replaced);
+                return Err(error);
+            }
+        },
+        None => None,
+    };
+    Ok((cmr, replaced, verification))
+}
+
+/// Read the file at the given path, or stdin if the path is `-`.
+fn read_input(path: &Path) -> Result<String, Error> {
+    if path == Path::new("-") {
+        let mut input = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut input)?;
+        Ok(input)
+    } else {
+        Ok(std::fs::read_to_string(path)?)
+    }
+}
+
+/// Advance the index past addresses that already received coins and warn about each of them.
+fn skip_used_indices(state: &mut State) -> Result<(), Error> {
+    for index in state.skip_used_indices()? {
+        eprintln!(
+            "Warning: skipping index {} because its address already received coins",
+            index
+        );
+    }
+    Ok(())
+}
+
+/// Print the error to stderr, or as JSON to stdout, and return its exit code.
+fn report(error: &Error, json: bool) -> ExitCode {
+    if json {
+        let output = serde_json::json!({
+            "error": {
+                "kind": error.kind(),
+                "message": error.to_string(),
+            }
+        });
+        println!("{}", output);
+    } else {
+        eprintln!("Error: {}", error);
+    }
+    ExitCode::from(error.exit_code())
+}
+
+fn main() -> ExitCode {
+    let (options, command) = parse::command();
+    let json = options.json;
+    let command = match command {
+        Ok(command) => command,
+        Err(error) => return report(&error, json),
+    };
+
+    match run(options, command) {
+        Ok(exit_code) => exit_code,
+        Err(error) => report(&error, json),
+    }
+}
+
+fn run(options: Options, command: Command) -> Result<ExitCode, Error> {
+    let mut config = Config::load(&options.config)?;
+    config.merge(options.overrides);
+    let wallet = options.wallet.clone();
+    if options.sat {
+        output::show_satoshi();
+    }
+    if options.debug_rpc {
+        rpc::enable_debug();
+    }
+    if options.deterministic_nonces {
+        spend::use_deterministic_nonces();
+    }
 
-    match command {
-        Command::New => {
+    let output = match command {
+        Command::New {
+            random_internal_key,
+        } => {
             let xpriv = DescriptorSecretKey::random()?;
-            let state = State::new(xpriv);
-            println!("Generating state.json");
-            state.save("state.json", true)?;
+            let unspendable_secret = random_internal_key.then(key::random_unspendable_secret);
+            let state = State::new(xpriv, unspendable_secret);
+            state.save(&wallet, true)?;
+            Output::Generated { wallet }
+        }
+        Command::Backup { path } => {
+            let state = load_state(&wallet, &config)?;
+            let mut passphrase = parse::read_new_passphrase("Backup passphrase: ")?;
+            let backup = state.export_backup(&path, &passphrase);
+            zeroize::Zeroize::zeroize(&mut passphrase);
+            backup?;
+            Output::Backup { path }
+        }
+        Command::Import { path } => {
+            let mut passphrase: String = parse::read_secret("Backup passphrase: ")?;
+            let state = State::import_backup(&path, &passphrase);
+            zeroize::Zeroize::zeroize(&mut passphrase);
+            let state = state?;
+            state.save(&wallet, true)?;
+            Output::Generated { wallet }
         }
-        Command::GetNewAddress => {
-            let mut state = State::load("state.json")?;
+        Command::ImportKey {
+            random_internal_key,
+        } => {
+            let xpriv: DescriptorSecretKey = parse::read_secret("Secret key: ")?;
+            xpriv.check_derivable()?;
+            let unspendable_secret = random_internal_key.then(key::random_unspendable_secret);
+            let state = State::new(xpriv, unspendable_secret);
+            state.save(&wallet, true)?;
+            Output::Generated { wallet }
+        }
+        Command::GetNewAddress {
+            count: Some(count),
+            skip_used,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            if skip_used {
+                skip_used_indices(&mut state)?;
+            }
+            let addresses = state.next_addresses(count)?;
+            state.save(&wallet, false)?;
+            Output::Addresses(addresses)
+        }
+        Command::GetNewAddress {
+            count: None,
+            skip_used,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
 
             let mut asm: Vec<_> = state.assembly().iter().collect();
             asm.sort();
 
-            let address = if !asm.is_empty()
-                && parse::prompt::<Choice>("Address of assembly fragment? y/n: ")?.into()
-            {
-                for (index, cmr) in asm.iter().enumerate() {
-                    println!("{}: {}", index, cmr);
-                }
+            let info =
+                if !asm.is_empty() && parse::ask("Address of assembly fragment? y/n: ", false)? {
+                    for (index, cmr) in asm.iter().enumerate() {
+                        eprintln!("{}: {}", index, cmr);
+                    }
 
-                let index: usize = parse::prompt("Assembly fragment index: ")?;
-                let cmr = asm.get(index).ok_or(Error::AssemblyOutOfBounds)?;
-                state
-                    .assembly()
-                    .get_address(cmr, state.network().address_params())
-                    .expect("set contains cmr")
+                    let index: usize = parse::prompt("Assembly fragment index: ")?;
+                    let cmr = asm.get(index).ok_or(Error::AssemblyOutOfBounds)?;
+                    let descriptor = state.assembly().get(cmr).expect("set contains cmr");
+                    AddressInfo::new(None, descriptor, state.network().address_params())
+                } else {
+                    if skip_used {
+                        skip_used_indices(&mut state)?;
+                    }
+                    state.next_address()?
+                };
+            state.save(&wallet, false)?;
+            Output::Address(info)
+        }
+        Command::GetBalance {
+            from_height,
+            since_last_scan,
+            by_address,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let from_height = if since_last_scan {
+                state.last_scanned_height().map_or(0, |h| h + 1)
             } else {
-                state.next_address()?
+                from_height.unwrap_or(0)
             };
+            if by_address {
+                let balances = spend::get_address_balances(&mut state, from_height)?;
+                state.save(&wallet, false)?;
+                Output::AddressBalances(balances)
+            } else {
+                let balance = Balance {
+                    spendable: spend::get_spendable_balance(&mut state, from_height)?,
+                    locked: spend::get_locked_balance(&mut state, from_height)?,
+                    pending: spend::get_pending_balance(&state)?,
+                    fragments: spend::get_fragment_balances(&mut state, from_height)?,
+                    assets: spend::get_asset_balances(&mut state, from_height)?,
+                    from_height,
+                };
 
-            println!("{}", address);
-            state.save("state.json", false)?;
+                // Cached results may be older than the chain tip, so only a new scan counts
+                if let Some(height) = state.fresh_scan_height() {
+                    state.set_last_scanned_height(height);
+                }
+                state.save(&wallet, false)?;
+                Output::Balance(balance)
+            }
         }
-        Command::GetBalance => {
-            let state = State::load("state.json")?;
-            let spendable_balance = spend::get_spendable_balance(&state)?;
-            let locked_balance = spend::get_locked_balance(&state)?;
-            println!("Spendable: {}", spendable_balance);
-            println!("Locked:    {}", locked_balance);
+        Command::ListUnspent {
+            order,
+            offset,
+            limit,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let descriptors = state.wallet_descriptors();
+            let utxo_set = state.scan(&descriptors)?;
+            let total = utxo_set.0.len();
+            let utxos = utxo_set.page(order, offset, limit).0;
+            state.save(&wallet, false)?;
+            Output::Unspent {
+                utxos,
+                offset,
+                total,
+            }
+        }
+        Command::SendToAddress {
+            recipient,
+            amount,
+            options,
+            yes,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let spend = spend::send_to_address(&mut state, recipient, amount, &options)?;
+            let output = confirm_and_broadcast(&mut state, spend, &options, yes)?;
+            state.save(&wallet, false)?;
+            output
+        }
+        Command::PlanSend {
+            recipient,
+            amount,
+            options,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            Output::Plan(spend::plan_send(&mut state, recipient, amount, &options)?)
+        }
+        Command::SendFromFile { path, options, yes } => {
+            let request = serde_json::from_str(&read_input(&path)?)?;
+            let mut state = load_state(&wallet, &config)?;
+            let spend = spend::send_payment_request(&mut state, request, &options)?;
+            let output = confirm_and_broadcast(&mut state, spend, &options, yes)?;
+            state.save(&wallet, false)?;
+            output
         }
-        Command::SendToAddress { send_to } => {
-            let mut state = State::load("state.json")?;
-            let txid = spend::send_to_address(&mut state, send_to)?;
-            println!("{}", txid);
-            state.save("state.json", false)?;
+        Command::SpeedUp {
+            txid,
+            fee_rate,
+            options,
+            yes,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let speed_up = spend::speed_up(&mut state, txid, fee_rate, &options)?;
+            let (hex, confirmed) = confirm(
+                state.network(),
+                &speed_up,
+                &speed_up.spend.tx,
+                &options,
+                yes,
+            )?;
+            let txid = if confirmed {
+                Some(spend::broadcast(&mut state, &speed_up.spend)?)
+            } else {
+                None
+            };
+            state.save(&wallet, false)?;
+            Output::SpeedUp {
+                speed_up,
+                txid,
+                hex: options.print_hex.then_some(hex),
+            }
+        }
+        Command::Consolidate {
+            max_inputs,
+            options,
+            yes,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let spend = spend::consolidate(&mut state, max_inputs, &options)?;
+            let output = confirm_and_broadcast(&mut state, spend, &options, yes)?;
+            state.save(&wallet, false)?;
+            output
         }
         Command::SetFee { fee } => {
-            let mut state = State::load("state.json")?;
+            let mut state = load_state(&wallet, &config)?;
             state.set_fee(fee);
-            println!("New fee: {}", fee);
-            state.save("state.json", false)?;
+            state.save(&wallet, false)?;
+            Output::Fee { fee }
         }
-        Command::SetRpc { rpc } => {
-            let mut state = State::load("state.json")?;
-            println!("New RPC connection: {}", rpc);
+        Command::SetRpc { rpc, force } => {
+            let mut state = load_state(&wallet, &config)?;
+            // A typo would lock the wallet out of the node until state.json is fixed by hand
+            if !force {
+                if let Err(error) = rpc.getblockchaininfo() {
+                    eprintln!("The node did not answer, so the connection was not saved (use --force to save anyway)");
+                    return Err(error);
+                }
+            }
+            let output = Output::Rpc {
+                url: rpc.url.clone(),
+                user: rpc.user.clone(),
+                password: rpc.pass.is_some(),
+            };
             state.set_rpc(rpc);
-            state.save("state.json", false)?;
+            state.save(&wallet, false)?;
+            output
         }
         Command::SetNetwork { network } => {
-            let mut state = State::load("state.json")?;
-            println!("New network: {}", network);
+            let mut state = load_state(&wallet, &config)?;
             state.set_network(network);
-            state.save("state.json", false)?;
+            state.save(&wallet, false)?;
+            Output::Network {
+                network,
+                changed: true,
+            }
         }
-        Command::ImportProgram { program } => {
-            let file = std::fs::read_to_string(program)?;
-            let forest = human_encoding::Forest::<simplicity::jet::Elements>::parse(&file)?;
-            let cmr = forest.roots()["main"].cmr();
+        Command::RefreshChainParams => {
+            let mut state = load_state(&wallet, &config)?;
+            let bitcoin_id = state.rpc().getsidechaininfo()?.pegged_asset;
+            let genesis_hash = state.rpc().getblockhash(0)?;
 
-            let mut state = State::load("state.json")?;
-            if state.assembly_mut().insert(cmr) {
-                println!("New CMR: {}", cmr);
+            let network = state.network();
+            if network.bitcoin_id() == bitcoin_id && network.genesis_hash() == genesis_hash {
+                Output::Network {
+                    network,
+                    changed: false,
+                }
+            } else {
+                // Keep the address parameters of the previous network
+                let network = Network::Custom {
+                    bitcoin_id,
+                    genesis_hash,
+                    address_params: network.address_params(),
+                };
+                state.set_network(network);
+                state.save(&wallet, false)?;
+                Output::Network {
+                    network,
+                    changed: true,
+                }
             }
-            state.save("state.json", false)?;
         }
-        Command::SatisfyProgram { program, witness } => {
-            let mut state = State::load("state.json")?;
+        Command::Info => {
+            let state = load_state(&wallet, &config)?;
+            let node = state
+                .rpc()
+                .getblockchaininfo()
+                .map(|info| NodeInfo {
+                    chain: info.chain,
+                    height: info.blocks,
+                })
+                .map_err(|error| error.to_string());
 
-            let file = std::fs::read_to_string(program)?;
-            let forest = human_encoding::Forest::<simplicity::jet::Elements>::parse(&file)?;
-            let cmr = forest.roots()["main"].cmr();
+            Output::Info(Info {
+                version: env!("CARGO_PKG_VERSION"),
+                network: state.network(),
+                rpc: state.rpc().to_string(),
+                account: state.account_name().to_string(),
+                node,
+                addresses: state.address_count(),
+                assembly_fragments: state.assembly().iter().count(),
+            })
+        }
+        Command::GetConfig => {
+            let state = load_state(&wallet, &config)?;
+            Output::Config(ConfigInfo {
+                network: state.network(),
+                fee: state.fee(),
+                max_fee_percent: state.max_fee_percent(),
+                rpc: state.rpc().to_string(),
+                scan_batch_size: state.scan_batch_size(),
+                scan_cache_ttl: state.scan_cache_ttl(),
+                account: state.account_name().to_string(),
+                next_index: state.account_next_index(),
+            })
+        }
+        Command::CheckWallet => {
+            // Loading normally would stop at the first problem with the keys
+            let state = State::load_unchecked(&wallet)?;
+            Output::WalletCheck {
+                problems: state.problems(),
+            }
+        }
+        Command::AbortScan => {
+            let state = load_state(&wallet, &config)?;
+            let aborted = state.rpc().scantxoutset_abort()?;
+            Output::AbortScan { aborted }
+        }
+        Command::NewAccount { name } => {
+            let mut state = load_state(&wallet, &config)?;
+            let xpriv = DescriptorSecretKey::random()?;
+            state.add_account(name.clone(), xpriv)?;
+            state.save(&wallet, false)?;
+            Output::Account {
+                name,
+                created: true,
+            }
+        }
+        Command::SetAccount { name } => {
+            let mut state = load_state(&wallet, &config)?;
+            state.set_account(&name)?;
+            state.save(&wallet, false)?;
+            Output::Account {
+                name,
+                created: false,
+            }
+        }
+        Command::DeriveAddress { index } => {
+            let state = load_state(&wallet, &config)?;
+            Output::Address(state.address_at(index)?)
+        }
+        Command::IsMine { address } => {
+            let state = load_state(&wallet, &config)?;
+            let script_pubkey = address.script_pubkey();
+            let fragment = state
+                .assembly()
+                .descriptors()
+                .find(|d| d.script_pubkey() == script_pubkey);
+            let mine = fragment.is_some()
+                || state
+                    .child_descriptors()
+                    .iter()
+                    .any(|d| d.script_pubkey() == script_pubkey);
+            let cmrs = fragment.map(descriptor::get_cmrs).unwrap_or_default();
+            Output::IsMine {
+                address,
+                mine,
+                cmrs,
+            }
+        }
+        Command::SignRawTransaction { tx, prevouts } => {
+            let state = load_state(&wallet, &config)?;
+            let inputs = tx.input.len();
+            let (tx, signed) = spend::sign_raw_transaction(&state, tx, &prevouts)?;
+            Output::SignedTransaction {
+                hex: rpc::tx_hex(&tx),
+                signed,
+                inputs,
+            }
+        }
+        Command::IssueAsset {
+            amount,
+            token_amount,
+            options,
+            yes,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let issuance = spend::issue_asset(&mut state, amount, token_amount, &options)?;
+            let (hex, confirmed) =
+                confirm(state.network(), &issuance, &issuance.tx, &options, yes)?;
+            let txid = if confirmed {
+                Some(spend::broadcast_issuance(&mut state, &issuance)?)
+            } else {
+                None
+            };
+            state.save(&wallet, false)?;
+            Output::Issuance {
+                issuance,
+                txid,
+                hex: options.print_hex.then_some(hex),
+            }
+        }
+        Command::Fund { amount, blocks } => {
+            let mut state = load_state(&wallet, &config)?;
+            if !matches!(state.network(), Network::Regtest) {
+                return Err(Error::NotRegtest);
+            }
 
-            if !state.assembly().contains(&cmr) {
-                return Err(Error::UnknownAssembly(cmr))?;
+            let address = state.next_address()?.address;
+            state.save(&wallet, false)?;
+            let txid = state.rpc().sendtoaddress(&address, amount)?;
+            let blocks = if 0 < blocks {
+                let node_address = state.rpc().getnewaddress()?;
+                state.rpc().generatetoaddress(blocks, &node_address)?
+            } else {
+                Vec::new()
+            };
+
+            Output::Fund {
+                amount,
+                address,
+                txid,
+                blocks,
+            }
+        }
+        Command::ForgetUtxo { outpoint, undo } => {
+            let mut state = load_state(&wallet, &config)?;
+            let changed = if undo {
+                state.remember_utxo(&outpoint)
+            } else {
+                state.forget_utxo(outpoint)
+            };
+            state.save(&wallet, false)?;
+            Output::Forget {
+                outpoint,
+                forgotten: !undo,
+                changed,
             }
+        }
+        Command::ImportProgram { program, verbose } => {
+            let file = read_input(&program)?;
+            let forest = human_encoding::Forest::<simplicity::jet::Elements>::parse(&file)?;
+            let main = &forest.roots()["main"];
+            let cmr = main.cmr();
 
-            let file = std::fs::read_to_string(witness)?;
-            let name_to_hex: HashMap<String, String> = serde_json::from_str(&file)?;
-            let name_to_value = name_to_hex
-                .into_iter()
-                .map(|(name, hex)| {
-                    Vec::<u8>::from_hex(&hex)
-                        .map_err(|err| Error::CouldNotParse(err.to_string()))
-                        .map(|bytes| (Arc::<str>::from(name), Value::from_slice(&bytes)))
-                })
-                .collect::<Result<HashMap<Arc<str>, Arc<Value>>, Error>>()?;
+            let program = verbose.then(|| ProgramInfo {
+                ty: main.arrow().to_string(),
+                nodes: program::node_count(main),
+                witnesses: program::witness_names(main)
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+            });
+
+            let timelocks = program::timelocks(main).into_iter().collect();
+
+            let mut state = load_state(&wallet, &config)?;
+            let new = state.import_assembly(cmr);
+            state.save(&wallet, false)?;
+            Output::Import {
+                cmr,
+                new,
+                program,
+                timelocks,
+            }
+        }
+        Command::ImportCmr { cmr } => {
+            let mut state = load_state(&wallet, &config)?;
+            let new = state.import_assembly(cmr);
+            state.save(&wallet, false)?;
+            Output::Import {
+                cmr,
+                new,
+                program: None,
+                timelocks: Vec::new(),
+            }
+        }
+        Command::SatisfyProgram {
+            mut pairs,
+            verify,
+            options,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let verify = verify.then_some(&options);
 
-            let program = forest.to_witness_node(&name_to_value)?;
-            let maybe_replaced = state.assembly_mut().insert_satisfaction(&program)?;
+            if pairs.len() == 1 {
+                let (program, witness) = pairs.remove(0);
+                let (cmr, replaced, verification) =
+                    satisfy_program(&mut state, &program, &witness, verify)?;
+                state.save(&wallet, false)?;
+                Output::Satisfaction {
+                    cmr,
+                    replaced,
+                    verification,
+                }
+            } else {
+                let results: Vec<_> = pairs
+                    .into_iter()
+                    .map(|(program, witness)| {
+                        match satisfy_program(&mut state, &program, &witness, verify) {
+                            Ok((cmr, replaced, verification)) => SatisfactionResult {
+                                program,
+                                cmr: Some(cmr),
+                                replaced: replaced.is_some(),
+                                verification,
+                                error: None,
+                            },
+                            Err(error) => SatisfactionResult {
+                                program,
+                                cmr: None,
+                                replaced: false,
+                                verification: None,
+                                error: Some(error.to_string()),
+                            },
+                        }
+                    })
+                    .collect();
+                if results.iter().any(|result| result.error.is_none()) {
+                    state.save(&wallet, false)?;
+                }
+                Output::Satisfactions(results)
+            }
+        }
+        Command::DecodeSatisfaction { source } => {
+            let satisfaction = match source {
+                SatisfactionSource::Stored(cmr) => {
+                    let state = load_state(&wallet, &config)?;
+                    if !state.assembly().contains(&cmr) {
+                        return Err(Error::UnknownAssembly(cmr));
+                    }
+                    state
+                        .assembly()
+                        .get_encoded_satisfaction(&cmr)
+                        .cloned()
+                        .ok_or(Error::MissingSatisfaction(cmr))?
+                }
+                SatisfactionSource::Encoded(satisfaction) => satisfaction,
+            };
 
-            if let Some(replaced) = maybe_replaced {
-                println!("Replaced old satisfaction {}", replaced);
+            let program = satisfaction.as_redeem_node();
+            Output::Decoded {
+                cmr: program.cmr(),
+                program: program.to_string(),
+                base64: satisfaction,
             }
-            println!("Inserted new satisfaction\n");
-            println!("Note that the wallet cannot check if the satisfaction is valid!");
-            println!("It is the responsibility of the user to provide a valid satisfaction.");
-            println!("The wallet will return an error if the satisfaction fails during spending.");
+        }
+        Command::SpendAssembly {
+            cmr,
+            address,
+            options,
+            yes,
+        } => {
+            let mut state = load_state(&wallet, &config)?;
+            let mut utxos = spend::get_assembly_utxos(&mut state, &cmr)?.0;
+
+            let utxo = match utxos.len() {
+                0 => return Err(Error::NotEnoughFunds),
+                1 => utxos.remove(0),
+                _ => {
+                    for (index, utxo) in utxos.iter().enumerate() {
+                        eprintln!("{}: {} {}", index, utxo.outpoint, utxo.amount);
+                    }
 
-            state.save("state.json", false)?;
+                    let index: usize = parse::prompt("UTXO index: ")?;
+                    if utxos.len() <= index {
+                        return Err(Error::UtxoOutOfBounds);
+                    }
+                    utxos.remove(index)
+                }
+            };
+
+            let spend = spend::sweep_utxo(&mut state, utxo, address, &options)?;
+            let output = confirm_and_broadcast(&mut state, spend, &options, yes)?;
+            state.save(&wallet, false)?;
+            output
         }
+    };
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    } else {
+        println!("{}", output);
     }
 
-    Ok(())
+    Ok(output.exit_code())
 }
@@ -4,12 +4,109 @@ use std::str::FromStr;
 use elements_miniscript as miniscript;
 use miniscript::bitcoin::hashes::{sha256, Hash};
 use miniscript::elements;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum Network {
     Regtest,
     Testnet,
+    /// Chain with non-default chain parameters
+    Custom {
+        bitcoin_id: elements::AssetId,
+        genesis_hash: elements::BlockHash,
+        #[serde(
+            default = "default_address_params",
+            serialize_with = "serialize_address_params",
+            deserialize_with = "deserialize_address_params"
+        )]
+        address_params: &'static elements::AddressParams,
+    },
+}
+
+/// Address parameters in a form that can be read from and written to files.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CustomAddressParams {
+    pub p2pkh_prefix: u8,
+    pub p2sh_prefix: u8,
+    pub blinded_prefix: u8,
+    pub bech_hrp: String,
+    pub blech_hrp: String,
+}
+
+impl CustomAddressParams {
+    /// Check that the human-readable parts are valid for bech32 and can be told apart.
+    pub fn validate(&self) -> Result<(), String> {
+        for hrp in [&self.bech_hrp, &self.blech_hrp] {
+            if hrp.is_empty() || 83 < hrp.len() {
+                return Err(format!("HRP must be 1 to 83 characters long: {}", hrp));
+            }
+            if !hrp
+                .chars()
+                .all(|c| matches!(c, '!'..='~') && !c.is_ascii_uppercase())
+            {
+                return Err(format!(
+                    "HRP must consist of lowercase printable ASCII characters: {}",
+                    hrp
+                ));
+            }
+        }
+        if self.bech_hrp == self.blech_hrp {
+            return Err("bech32 and blech32 HRPs must differ".to_string());
+        }
+        Ok(())
+    }
+
+    /// Convert into address parameters that live until the wallet exits.
+    ///
+    /// Address parameters require static strings.
+    /// The wallet exits after each command, so leaking the few bytes is fine.
+    pub fn leak(self) -> &'static elements::AddressParams {
+        Box::leak(Box::new(elements::AddressParams {
+            p2pkh_prefix: self.p2pkh_prefix,
+            p2sh_prefix: self.p2sh_prefix,
+            blinded_prefix: self.blinded_prefix,
+            bech_hrp: Box::leak(self.bech_hrp.into_boxed_str()),
+            blech_hrp: Box::leak(self.blech_hrp.into_boxed_str()),
+        }))
+    }
+}
+
+impl From<&elements::AddressParams> for CustomAddressParams {
+    fn from(params: &elements::AddressParams) -> Self {
+        Self {
+            p2pkh_prefix: params.p2pkh_prefix,
+            p2sh_prefix: params.p2sh_prefix,
+            blinded_prefix: params.blinded_prefix,
+            bech_hrp: params.bech_hrp.to_string(),
+            blech_hrp: params.blech_hrp.to_string(),
+        }
+    }
+}
+
+fn default_address_params() -> &'static elements::AddressParams {
+    &elements::AddressParams::ELEMENTS
+}
+
+fn serialize_address_params<S>(
+    params: &&'static elements::AddressParams,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    CustomAddressParams::from(*params).serialize(serializer)
+}
+
+pub fn deserialize_address_params<'de, D>(
+    deserializer: D,
+) -> Result<&'static elements::AddressParams, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let params = CustomAddressParams::deserialize(deserializer)?;
+    params.validate().map_err(serde::de::Error::custom)?;
+    Ok(params.leak())
 }
 
 const REGTEST_BITCOIN_ID: [u8; 32] = [
@@ -45,6 +142,24 @@ impl Network {
         match self {
             Network::Regtest => &elements::AddressParams::ELEMENTS,
             Network::Testnet => &TESTNET_ADDRESS_PARAMS,
+            Network::Custom { address_params, .. } => address_params,
+        }
+    }
+
+    /// Return whether coins on this network have real value.
+    ///
+    /// There is no dedicated Liquid network yet,
+    /// so a custom network with Liquid address parameters counts as mainnet.
+    pub fn is_mainnet(self) -> bool {
+        self.address_params() == &elements::AddressParams::LIQUID
+    }
+
+    /// Return the same chain with different address parameters.
+    pub fn with_address_params(self, address_params: &'static elements::AddressParams) -> Self {
+        Network::Custom {
+            bitcoin_id: self.bitcoin_id(),
+            genesis_hash: self.genesis_hash(),
+            address_params,
         }
     }
 
@@ -57,6 +172,7 @@ impl Network {
         let bytes = match self {
             Network::Regtest => REGTEST_BITCOIN_ID,
             Network::Testnet => TESTNET_BITCOIN_ID,
+            Network::Custom { bitcoin_id, .. } => return bitcoin_id,
         };
         elements::AssetId::from_inner(sha256::Midstate(bytes))
     }
@@ -70,6 +186,7 @@ impl Network {
         let bytes = match self {
             Network::Regtest => REGTEST_GENESIS_HASH,
             Network::Testnet => TESTNET_GENESIS_HASH,
+            Network::Custom { genesis_hash, .. } => return genesis_hash,
         };
         elements::BlockHash::from_byte_array(bytes)
     }
@@ -92,6 +209,21 @@ impl fmt::Display for Network {
         match self {
             Network::Regtest => f.write_str("regtest"),
             Network::Testnet => f.write_str("testnet"),
+            Network::Custom {
+                bitcoin_id,
+                genesis_hash,
+                address_params,
+            } => {
+                write!(
+                    f,
+                    "custom (bitcoin id {}, genesis hash {}, address prefix {})",
+                    bitcoin_id, genesis_hash, address_params.bech_hrp
+                )?;
+                if self.is_mainnet() {
+                    write!(f, " MAINNET")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,586 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use elements_miniscript as miniscript;
+use miniscript::{bitcoin, elements};
+use serde::Serialize;
+
+use crate::descriptor::{self, SerdeWitnessNode};
+use crate::network::Network;
+use crate::program::Timelock;
+use crate::spend::{
+    AddressBalance, AssetBalance, FragmentBalance, Issuance, Plan, SpeedUp, Spend, Verification,
+};
+use crate::state::{AddressInfo, Utxo};
+
+/// Whether to print amounts of bitcoin in satoshi instead of L-BTC.
+static SATOSHI: AtomicBool = AtomicBool::new(false);
+
+/// Print amounts of bitcoin in satoshi instead of L-BTC.
+///
+/// JSON output always uses satoshi.
+pub fn show_satoshi() {
+    SATOSHI.store(true, Ordering::Relaxed);
+}
+
+/// Amount of bitcoin as it is printed as text, such as `0.00042000 L-BTC` or `42000 sat`.
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayAmount(pub bitcoin::Amount);
+
+impl fmt::Display for DisplayAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sat = self.0.to_sat();
+        if SATOSHI.load(Ordering::Relaxed) {
+            write!(f, "{} sat", sat)
+        } else {
+            write!(f, "{}.{:08} L-BTC", sat / 100_000_000, sat % 100_000_000)
+        }
+    }
+}
+
+/// Result of a command.
+///
+/// The result is printed as text by default and as JSON with `--json`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Output {
+    Generated {
+        wallet: PathBuf,
+    },
+    Backup {
+        path: PathBuf,
+    },
+    Address(AddressInfo),
+    Addresses(Vec<AddressInfo>),
+    Balance(Balance),
+    Unspent {
+        utxos: Vec<Utxo>,
+        offset: usize,
+        /// Number of UTXOs before pagination
+        total: usize,
+    },
+    Spend {
+        #[serde(flatten)]
+        spend: Spend,
+        /// `None` if the user declined to broadcast
+        txid: Option<elements::Txid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hex: Option<String>,
+    },
+    Fee {
+        #[serde(with = "bitcoin::amount::serde::as_sat")]
+        fee: bitcoin::Amount,
+    },
+    Rpc {
+        url: String,
+        user: String,
+        password: bool,
+    },
+    Network {
+        network: Network,
+        changed: bool,
+    },
+    Import {
+        cmr: simplicity::Cmr,
+        new: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        program: Option<ProgramInfo>,
+        /// Timelocks that the program checks
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        timelocks: Vec<Timelock>,
+    },
+    Satisfaction {
+        cmr: simplicity::Cmr,
+        replaced: Option<SerdeWitnessNode<simplicity::jet::Elements>>,
+        /// `None` without `--verify`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        verification: Option<Verification>,
+    },
+    Satisfactions(Vec<SatisfactionResult>),
+    Decoded {
+        cmr: simplicity::Cmr,
+        base64: SerdeWitnessNode<simplicity::jet::Elements>,
+        program: String,
+    },
+    Info(Info),
+    Plan(Plan),
+    AddressBalances(Vec<AddressBalance>),
+    Config(ConfigInfo),
+    AbortScan {
+        aborted: bool,
+    },
+    WalletCheck {
+        problems: Vec<String>,
+    },
+    Account {
+        name: String,
+        created: bool,
+    },
+    IsMine {
+        address: elements::Address,
+        mine: bool,
+        /// CMRs of the Simplicity leaves of the address, if it is an assembly fragment
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        cmrs: Vec<simplicity::Cmr>,
+    },
+    SignedTransaction {
+        hex: String,
+        /// Number of inputs that the wallet signed
+        signed: usize,
+        inputs: usize,
+    },
+    SpeedUp {
+        #[serde(flatten)]
+        speed_up: SpeedUp,
+        /// `None` if the user declined to broadcast
+        txid: Option<elements::Txid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hex: Option<String>,
+    },
+    Issuance {
+        #[serde(flatten)]
+        issuance: Issuance,
+        /// `None` if the user declined to broadcast
+        txid: Option<elements::Txid>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hex: Option<String>,
+    },
+    Fund {
+        #[serde(with = "bitcoin::amount::serde::as_sat")]
+        amount: bitcoin::Amount,
+        address: elements::Address,
+        txid: elements::Txid,
+        blocks: Vec<elements::BlockHash>,
+    },
+    Forget {
+        outpoint: elements::OutPoint,
+        forgotten: bool,
+        changed: bool,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Balance {
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub spendable: bitcoin::Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub locked: bitcoin::Amount,
+    /// Incoming coins that are not confirmed yet
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub pending: bitcoin::Amount,
+    pub fragments: Vec<FragmentBalance>,
+    pub assets: Vec<AssetBalance>,
+    pub from_height: u64,
+}
+
+#[derive(Serialize)]
+pub struct Info {
+    pub version: &'static str,
+    pub network: Network,
+    pub rpc: String,
+    /// Name of the active account
+    pub account: String,
+    /// Chain and block height reported by the node, or the error when it could not be reached
+    pub node: Result<NodeInfo, String>,
+    pub addresses: u32,
+    pub assembly_fragments: usize,
+}
+
+/// Settings in effect, after applying the config file and command line options.
+#[derive(Serialize)]
+pub struct ConfigInfo {
+    pub network: Network,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
+    pub max_fee_percent: u64,
+    /// Connection with the password masked
+    pub rpc: String,
+    pub scan_batch_size: usize,
+    pub scan_cache_ttl: u64,
+    pub account: String,
+    pub next_index: u32,
+}
+
+#[derive(Serialize)]
+pub struct NodeInfo {
+    pub chain: String,
+    pub height: u64,
+}
+
+/// Outcome of satisfying one of several programs.
+#[derive(Serialize)]
+pub struct SatisfactionResult {
+    pub program: PathBuf,
+    pub cmr: Option<simplicity::Cmr>,
+    /// Whether an old satisfaction was replaced
+    pub replaced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification: Option<Verification>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ProgramInfo {
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub nodes: usize,
+    pub witnesses: Vec<String>,
+}
+
+/// Return the CMRs of the leaves of a fragment, separated by commas.
+pub fn join_cmrs(cmrs: &[simplicity::Cmr]) -> String {
+    cmrs.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Output {
+    /// Return the exit code of the process after printing this output.
+    ///
+    /// Commands that handle several items at once fail with exit code 1
+    /// if any item failed, after printing the results of all items.
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Output::Satisfactions(results) if results.iter().any(|r| r.error.is_some()) => {
+                ExitCode::FAILURE
+            }
+            _ => ExitCode::SUCCESS,
+        }
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Generated { wallet } => write!(f, "Generated {}", wallet.display()),
+            Output::Backup { path } => write!(
+                f,
+                "Wrote backup to {}\nThe backup contains the secret keys in plain, so keep it safe!",
+                path.display()
+            ),
+            Output::Address(info) => write!(f, "{}", info),
+            Output::Addresses(infos) => {
+                for (i, info) in infos.iter().enumerate() {
+                    if 0 < i {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{}", info)?;
+                }
+                Ok(())
+            }
+            Output::Balance(balance) => write!(f, "{}", balance),
+            Output::Plan(plan) => write!(f, "{}", plan),
+            Output::AddressBalances(balances) if balances.is_empty() => {
+                write!(f, "No address holds coins")
+            }
+            Output::AddressBalances(balances) => {
+                for (i, balance) in balances.iter().enumerate() {
+                    if 0 < i {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", balance)?;
+                }
+                Ok(())
+            }
+            Output::Spend { txid, hex, .. }
+            | Output::SpeedUp { txid, hex, .. }
+            | Output::Issuance { txid, hex, .. } => {
+                match txid {
+                    Some(txid) => write!(f, "{}", txid)?,
+                    None => write!(f, "Transaction was not broadcast")?,
+                }
+                if let Some(hex) = hex {
+                    write!(f, "\n{}", hex)?;
+                }
+                Ok(())
+            }
+            Output::IsMine {
+                address,
+                mine,
+                cmrs,
+            } => match (mine, cmrs.is_empty()) {
+                (_, false) => write!(
+                    f,
+                    "{} belongs to the wallet (assembly fragment {})",
+                    address,
+                    join_cmrs(cmrs)
+                ),
+                (true, true) => write!(f, "{} belongs to the wallet", address),
+                (false, true) => write!(f, "{} does not belong to the wallet", address),
+            },
+            Output::SignedTransaction {
+                hex,
+                signed,
+                inputs,
+            } => write!(f, "{}\nSigned {} of {} inputs", hex, signed, inputs),
+            Output::Fee { fee } => write!(f, "New fee: {}", DisplayAmount(*fee)),
+            Output::Rpc {
+                url,
+                user,
+                password,
+            } => {
+                write!(f, "New RPC connection: {}@{}", user, url)?;
+                if *password {
+                    write!(f, " with password")?;
+                }
+                Ok(())
+            }
+            Output::Network { network, changed } => {
+                if *changed {
+                    write!(f, "New network: {}", network)
+                } else {
+                    write!(f, "Chain parameters match network: {}", network)
+                }
+            }
+            Output::Import {
+                cmr,
+                new,
+                program,
+                timelocks,
+            } => {
+                if let Some(program) = program {
+                    writeln!(f, "CMR:       {}", cmr)?;
+                    writeln!(f, "Type:      {}", program.ty)?;
+                    writeln!(f, "Nodes:     {}", program.nodes)?;
+                    if program.witnesses.is_empty() {
+                        writeln!(f, "Witnesses: none")?;
+                    } else {
+                        writeln!(f, "Witnesses: {}", program.witnesses.join(", "))?;
+                    }
+                }
+                for timelock in timelocks {
+                    writeln!(f, "Requires:  {}", timelock)?;
+                }
+                if *new {
+                    write!(f, "New CMR: {}", cmr)
+                } else {
+                    write!(f, "Known CMR: {}", cmr)
+                }
+            }
+            Output::Satisfaction {
+                replaced,
+                verification,
+                ..
+            } => {
+                if let Some(replaced) = replaced {
+                    writeln!(f, "Replaced old satisfaction {}", replaced)?;
+                }
+                match verification {
+                    Some(Verification::Passed) => {
+                        return write!(f, "Inserted new satisfaction, {}", Verification::Passed)
+                    }
+                    Some(verification) => writeln!(f, "Inserted new satisfaction, {}\n", verification)?,
+                    None => writeln!(f, "Inserted new satisfaction\n")?,
+                }
+                writeln!(
+                    f,
+                    "Note that the wallet cannot check if the satisfaction is valid!"
+                )?;
+                writeln!(
+                    f,
+                    "It is the responsibility of the user to provide a valid satisfaction."
+                )?;
+                write!(
+                    f,
+                    "The wallet will return an error if the satisfaction fails during spending."
+                )
+            }
+            Output::Satisfactions(results) => {
+                for result in results {
+                    if let Some(error) = &result.error {
+                        writeln!(f, "Failed {}: {}", result.program.display(), error)?;
+                    } else if let Some(cmr) = &result.cmr {
+                        let verb = if result.replaced {
+                            "Replaced"
+                        } else {
+                            "Inserted"
+                        };
+                        write!(f, "{} satisfaction of {}", verb, cmr)?;
+                        match &result.verification {
+                            Some(verification) => writeln!(f, ", {}", verification)?,
+                            None => writeln!(f)?,
+                        }
+                    }
+                }
+                let unverified = results.iter().any(|result| {
+                    result.error.is_none()
+                        && !matches!(result.verification, Some(Verification::Passed))
+                });
+                if unverified {
+                    write!(
+                        f,
+                        "\nThe wallet cannot check if unverified satisfactions are valid!"
+                    )?;
+                }
+                Ok(())
+            }
+            Output::Info(info) => write!(f, "{}", info),
+            Output::Config(config) => write!(f, "{}", config),
+            Output::AbortScan { aborted: true } => write!(f, "Aborted the running UTXO set scan"),
+            Output::AbortScan { aborted: false } => write!(f, "No UTXO set scan was running"),
+            Output::WalletCheck { problems } if problems.is_empty() => {
+                write!(f, "No problems found")
+            }
+            Output::WalletCheck { problems } => {
+                write!(f, "Found {} problems:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+            Output::Account {
+                name,
+                created: true,
+            } => write!(f, "Created account {} and switched to it", name),
+            Output::Account {
+                name,
+                created: false,
+            } => write!(f, "Switched to account {}", name),
+            Output::Fund {
+                amount,
+                address,
+                txid,
+                blocks,
+            } => {
+                write!(
+                    f,
+                    "Sent {} to {}: {}",
+                    DisplayAmount(*amount),
+                    address,
+                    txid
+                )?;
+                if !blocks.is_empty() {
+                    write!(f, "\nMined {} blocks", blocks.len())?;
+                }
+                Ok(())
+            }
+            Output::Unspent {
+                utxos,
+                offset,
+                total,
+            } => {
+                write!(f, "UTXOs:")?;
+                for utxo in utxos {
+                    write!(
+                        f,
+                        "\n  {}  {}  height {}",
+                        utxo.outpoint,
+                        DisplayAmount(utxo.amount),
+                        utxo.height
+                    )?;
+                    let cmrs = descriptor::get_cmrs(&utxo.descriptor);
+                    if !cmrs.is_empty() {
+                        write!(f, "  assembly {}", join_cmrs(&cmrs))?;
+                    }
+                }
+                if utxos.len() == *total {
+                    Ok(())
+                } else if utxos.is_empty() {
+                    write!(f, "\nNo UTXOs after the first {} of {}", offset, total)
+                } else {
+                    write!(
+                        f,
+                        "\nShowing {}–{} of {}",
+                        offset + 1,
+                        offset + utxos.len(),
+                        total
+                    )
+                }
+            }
+            Output::Forget {
+                outpoint,
+                forgotten,
+                changed,
+            } => match (forgotten, changed) {
+                (true, true) => write!(f, "Forgot UTXO {}", outpoint),
+                (true, false) => write!(f, "UTXO was already forgotten: {}", outpoint),
+                (false, true) => write!(f, "Remembered UTXO {}", outpoint),
+                (false, false) => write!(f, "UTXO was not forgotten: {}", outpoint),
+            },
+            Output::Decoded {
+                cmr,
+                base64,
+                program,
+            } => {
+                writeln!(f, "CMR:     {}", cmr)?;
+                writeln!(f, "Base64:  {}", base64)?;
+                write!(f, "Program:\n{}", program)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Version:    {}", self.version)?;
+        writeln!(f, "Network:    {}", self.network)?;
+        writeln!(f, "RPC:        {}", self.rpc)?;
+        writeln!(f, "Account:    {}", self.account)?;
+        match &self.node {
+            Ok(node) => {
+                writeln!(f, "Node:       connected ({})", node.chain)?;
+                writeln!(f, "Height:     {}", node.height)?;
+            }
+            Err(error) => writeln!(f, "Node:       unreachable ({})", error)?,
+        }
+        writeln!(f, "Addresses:  {}", self.addresses)?;
+        write!(f, "Fragments:  {}", self.assembly_fragments)
+    }
+}
+
+impl fmt::Display for ConfigInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Network:          {}", self.network)?;
+        writeln!(f, "Fee:              {}", DisplayAmount(self.fee))?;
+        writeln!(f, "Max fee percent:  {}%", self.max_fee_percent)?;
+        writeln!(f, "RPC:              {}", self.rpc)?;
+        writeln!(f, "Scan batch size:  {}", self.scan_batch_size)?;
+        writeln!(f, "Scan cache TTL:   {} s", self.scan_cache_ttl)?;
+        writeln!(f, "Account:          {}", self.account)?;
+        write!(f, "Next index:       {}", self.next_index)
+    }
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Spendable: {}", DisplayAmount(self.spendable))?;
+        writeln!(f, "Locked:    {}", DisplayAmount(self.locked))?;
+        write!(f, "Pending:   {}", DisplayAmount(self.pending))?;
+
+        if !self.fragments.is_empty() {
+            write!(f, "\n\nAssembly fragments:")?;
+            for balance in &self.fragments {
+                let status = if balance.spendable {
+                    "spendable"
+                } else {
+                    "locked"
+                };
+                write!(
+                    f,
+                    "\n{} {:9} {}",
+                    balance.cmr,
+                    status,
+                    DisplayAmount(balance.amount)
+                )?;
+            }
+        }
+        if !self.assets.is_empty() {
+            write!(f, "\n\nIssued assets:")?;
+            for balance in &self.assets {
+                let kind = if balance.token { "token" } else { "asset" };
+                write!(f, "\n{} {:5} {}", balance.asset, kind, balance.amount)?;
+            }
+        }
+        if 0 < self.from_height {
+            write!(
+                f,
+                "\nOnly counting coins confirmed at height {} or later",
+                self.from_height
+            )?;
+        }
+        Ok(())
+    }
+}
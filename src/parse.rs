@@ -1,101 +1,1018 @@
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use elements_miniscript::elements::hex::FromHex;
+use elements_miniscript::{bitcoin, elements};
 use lexopt::prelude::*;
 
 use crate::error::Error;
+use crate::network::{CustomAddressParams, Network};
 use crate::rpc::Connection;
-use crate::spend::Payment;
-use crate::Command;
+use crate::spend::{CoinSource, Recipient, SendOptions, UtxoOrder, DEFAULT_MAX_INPUTS};
+use crate::{Command, Options, SatisfactionSource};
 
-const HELP: &str = r#"Usage: simpiwallet [new | getnewaddress | getbalance | sendtoaddress | setfee | setrpc | setnetwork | importprogram | satisfyprogram | help] args..."#;
-const NEW_HELP: &str = "simpiwallet new";
-const GET_NEW_ADDRESS_HELP: &str = "simpiwallet getnewaddress";
-const GET_BALANCE_HELP: &str = "simpiwallet getbalance";
-const SEND_TO_ADDRESS_HELP: &str = "simpiwallet sendtoaddress ADDRESS AMOUNT";
-const SET_FEE_HELP: &str = "simpiwallet setfee AMOUNT";
-const SET_RPC_HELP: &str = "simpiwallet setrpc URL PORT USERNAME [PASSWORD]";
-const SET_NETWORK_HELP: &str = "simpiwallet setnetwork [regtest | testnet]";
-const IMPORT_PROGRAM_HELP: &str = r#"simpiwallet importprogram PROGRAM
+const HELP: &str = r#"Usage: simpiwallet [OPTIONS] [new | importkey | backup | import | getnewaddress | getbalance | listunspent | sendtoaddress | sendtoscript | speedup | consolidate | setfee | setrpc | setnetwork | refreshchainparams | info | getconfig | checkwallet | abortscan | ismine | deriveaddress | signrawtransaction | issueasset | newaccount | setaccount | fund | forgetutxo | importprogram | importcmr | satisfyprogram | decodesatisfaction | spendassembly | help] args...
+
+Options:
+    --wallet PATH   path to state file (default: $WALLET_FILE or state.json)
+    --config PATH   path to config file (default: simpiwallet.toml)
+    --fee AMOUNT    fee to use instead of the stored fee
+    --network NAME  network to use instead of the stored network ("regtest" or "testnet";
+                    switch to a custom network with setnetwork custom)
+    --refresh       scan the UTXO set even if there are recent cached results
+    --json          print the result of the command as JSON
+    -y, --yes       answer every prompt with its default and broadcast without asking
+    --sat           print amounts in satoshi instead of L-BTC
+    --debug-rpc     print RPC requests and raw responses to stderr
+    --deterministic-nonces
+                    sign without random data, so signatures are reproducible"#;
+const NEW_HELP: &str = r#"simpiwallet new [--random-internal-key]
+
+Options:
+    --random-internal-key  hide the unspendable internal key behind a random tweak"#;
+const IMPORT_KEY_HELP: &str = r#"simpiwallet importkey [--random-internal-key]
+
+Create a wallet around an existing secret key, which is read from stdin.
+The key must be an xpriv with a wildcard, such as "tprv.../84'/0'/0'/*".
+
+Options:
+    --random-internal-key  hide the unspendable internal key behind a random tweak"#;
+const BACKUP_HELP: &str = r#"simpiwallet backup PATH
+
+Write a copy of the wallet to a new file at PATH, which can be restored with import.
+The copy contains the keys of all accounts, the network and the assembly fragments
+with their satisfactions. It is encrypted with a passphrase that is read from stdin.
+The RPC password is left out; set it again with setrpc after importing.
+
+Positional arguments:
+    PATH  path of the backup file, which must not exist yet"#;
+const IMPORT_HELP: &str = r#"simpiwallet import PATH
+
+Create a wallet from a backup that was written by backup.
+The passphrase of the backup is read from stdin.
+
+Positional arguments:
+    PATH  path of the backup file"#;
+const GET_NEW_ADDRESS_HELP: &str = r#"simpiwallet getnewaddress [--json] [--count N] [--skip-used]
+
+Options:
+    --json       print address, derivation index, descriptor and script pubkey as JSON
+    --count N    derive N fresh wallet addresses at once (skips the assembly prompt)
+    --skip-used  ask the node for coins on the next addresses and skip addresses in use"#;
+const GET_BALANCE_HELP: &str = r#"simpiwallet getbalance [--from-height HEIGHT | --since-last-scan] [--by-address]
+
+Options:
+    --from-height HEIGHT  only count coins confirmed at HEIGHT or later
+    --since-last-scan     only count coins confirmed since the last getbalance
+    --by-address          list each address that holds coins with its amount"#;
+const LIST_UNSPENT_HELP: &str = r#"simpiwallet listunspent [--sort height | amount] [--offset N] [--limit N]
+
+List the confirmed UTXOs of the wallet, including those of locked assembly fragments.
+
+Options:
+    --sort ORDER  sort oldest first ("height", default) or largest first ("amount")
+    --offset N    skip the first N UTXOs
+    --limit N     list at most N UTXOs"#;
+const SEND_TO_ADDRESS_HELP: &str = r#"simpiwallet sendtoaddress (ADDRESS AMOUNT | --from-file PATH) [--yes] [--force] [--no-check] [--hex] [--save-hex PATH] [--change-address ADDRESS] [--data HEX] [--coins KIND] [--skip-mempool-spends] [--plan]
+
+Positional arguments:
+    ADDRESS  address that receives the funds
+    AMOUNT   amount with unit (such as "1000sat"), "max", "max-AMOUNT" or a percentage such as "50%"
+
+Options:
+    --from-file PATH  read the outputs and other parameters from the JSON file at PATH ("-" for stdin, which requires --yes)
+    -y, --yes         broadcast without asking for confirmation
+    --force           send even if the fee is unreasonably high
+    --no-check        broadcast without asking the node if it would accept the transaction
+    --hex             also print the signed transaction in hex
+    --save-hex PATH   write the signed transaction in hex to PATH
+    --change-address ADDRESS
+                      send change to ADDRESS instead of a fresh address of the wallet
+    --data HEX        include an OP_RETURN output with up to 80 bytes of data
+    --coins KIND      spend only "keys" or only "assembly" coins (default: "any")
+    --skip-mempool-spends
+                      skip coins that an unconfirmed transaction in the mempool already spends
+    --plan            only print the selected UTXOs, the change and the fee, without signing"#;
+const SEND_TO_SCRIPT_HELP: &str = r#"simpiwallet sendtoscript SCRIPT AMOUNT [--yes] [--force] [--no-check] [--hex] [--save-hex PATH] [--change-address ADDRESS] [--data HEX] [--coins KIND] [--skip-mempool-spends] [--plan]
+
+Send to an output script that has no address, such as a non-standard script.
+Takes the same options as sendtoaddress.
+
+Positional arguments:
+    SCRIPT   output script in hex, used as-is
+    AMOUNT   amount with unit (such as "1000sat"), "max", "max-AMOUNT" or a percentage such as "50%""#;
+const SPEED_UP_HELP: &str = r#"simpiwallet speedup TXID [--fee-rate RATE] [--yes] [--force] [--no-check] [--hex] [--save-hex PATH]
+
+Speed up an unconfirmed transaction by spending its output to the wallet
+in a child transaction that pays the fee (child pays for parent).
+The child pays enough to lift both transactions together to the target fee rate.
+
+Positional arguments:
+    TXID  ID of the unconfirmed transaction
+
+Options:
+    --fee-rate RATE   target fee rate in sat/vB (default: twice the minimum of the mempool)
+    -y, --yes         broadcast without asking for confirmation
+    --force           send even if the fee is unreasonably high
+    --no-check        broadcast without asking the node if it would accept the transaction
+    --hex             also print the signed transaction in hex
+    --save-hex PATH   write the signed transaction in hex to PATH"#;
+const CONSOLIDATE_HELP: &str = r#"simpiwallet consolidate [--max-inputs N] [--coins KIND] [--skip-mempool-spends] [--yes] [--force] [--no-check] [--hex] [--save-hex PATH]
+
+Spend the smallest UTXOs to a fresh address of the wallet, paying the fee from their total.
+
+Options:
+    --max-inputs N    spend at most N UTXOs (default: 20)
+    --coins KIND      spend only "keys" or only "assembly" coins (default: "any")
+    --skip-mempool-spends
+                      skip coins that an unconfirmed transaction in the mempool already spends
+    -y, --yes         broadcast without asking for confirmation
+    --force           send even if the fee is unreasonably high
+    --no-check        broadcast without asking the node if it would accept the transaction
+    --hex             also print the signed transaction in hex
+    --save-hex PATH   write the signed transaction in hex to PATH"#;
+const SET_FEE_HELP: &str = r#"simpiwallet setfee AMOUNT
+
+Positional arguments:
+    AMOUNT  amount with unit, such as "1000sat" or "0.00001btc""#;
+const SET_RPC_HELP: &str = r#"simpiwallet setrpc URL USERNAME [PASSWORD] [--force]
+
+Save the RPC connection after checking that the node answers.
+
+Positional arguments:
+    URL       address of the node, such as "localhost:18443"
+    USERNAME  RPC user name
+    PASSWORD  RPC password; prompted without echo if omitted, so it is required with --yes
+              (passing it as an argument leaks it into the shell history)
+
+Options:
+    --force  save the connection without checking it"#;
+const SET_NETWORK_HELP: &str = r#"simpiwallet setnetwork [regtest | testnet | custom BITCOIN_ID GENESIS_HASH] [ADDRESS OPTIONS]
+
+Positional arguments:
+    BITCOIN_ID    output of `elements-cli getsidechaininfo | jq --raw-output '.pegged_asset'`
+    GENESIS_HASH  output of `elements-cli getblockhash 0`
+
+Address options (override the address parameters of the network):
+    --p2pkh-prefix N    prefix of base58 P2PKH addresses
+    --p2sh-prefix N     prefix of base58 P2SH addresses
+    --blinded-prefix N  prefix of base58 blinded addresses
+    --bech-hrp HRP      human-readable part of bech32 addresses
+    --blech-hrp HRP     human-readable part of blech32 addresses"#;
+const REFRESH_CHAIN_PARAMS_HELP: &str = r#"simpiwallet refreshchainparams
+
+Fetch the bitcoin asset id and the genesis hash from the node.
+Switch to a custom network if they differ from the configured network."#;
+const INFO_HELP: &str = r#"simpiwallet info
+
+Print the wallet version, network and RPC connection,
+and check if the node can be reached."#;
+const GET_CONFIG_HELP: &str = r#"simpiwallet getconfig
+
+Print the settings in effect: the stored settings,
+overridden by the config file and the command line options."#;
+const CHECK_WALLET_HELP: &str = r#"simpiwallet checkwallet
+
+Check the state file for problems and list all of them:
+keys that don't match the descriptors, assembly fragments without CMR,
+satisfactions stored for the wrong fragment, and unreasonable settings."#;
+const ABORT_SCAN_HELP: &str = r#"simpiwallet abortscan
+
+Abort the UTXO set scan that is running on the node,
+for instance one that was started by an interrupted wallet command."#;
+const IS_MINE_HELP: &str = r#"simpiwallet ismine ADDRESS
+
+Check if ADDRESS belongs to the wallet.
+This includes the addresses that were handed out so far and the addresses of assembly fragments.
+
+Positional arguments:
+    ADDRESS  address to check"#;
+const DERIVE_ADDRESS_HELP: &str = r#"simpiwallet deriveaddress INDEX
+
+Print the address at INDEX of the active account, without marking it as used.
+Use this to check an address against one that was computed elsewhere.
+
+Positional arguments:
+    INDEX  non-hardened derivation index"#;
+const SIGN_RAW_TRANSACTION_HELP: &str = r#"simpiwallet signrawtransaction TXHEX PREVOUTS
+
+Sign the inputs of a transaction that spend coins of the wallet and print the transaction in hex.
+Other inputs are left as they are.
+
+Positional arguments:
+    TXHEX     transaction in hex
+    PREVOUTS  JSON array with the output that each input spends, in order:
+              [{"script_pubkey": HEX, "amount": SATOSHI, "asset": ASSET_ID}, ...]
+              (the asset defaults to bitcoin)"#;
+const ISSUE_ASSET_HELP: &str = r#"simpiwallet issueasset AMOUNT [--token-amount N] [--skip-mempool-spends] [--yes] [--no-check] [--hex] [--save-hex PATH]
+
+Issue a new asset to a fresh address of the wallet. The fee is paid in bitcoin.
+
+Positional arguments:
+    AMOUNT  amount of the asset in its smallest unit
+
+Options:
+    --token-amount N  also issue N reissuance tokens (default: 0)
+    --skip-mempool-spends
+                      skip coins that an unconfirmed transaction in the mempool already spends
+    -y, --yes         broadcast without asking for confirmation
+    --no-check        broadcast without asking the node if it would accept the transaction
+    --hex             also print the signed transaction in hex
+    --save-hex PATH   write the signed transaction in hex to PATH"#;
+const NEW_ACCOUNT_HELP: &str = r#"simpiwallet newaccount NAME
+
+Create an account with a fresh key and make it the active account.
+New addresses and change are derived from the active account.
+The balance includes the coins of all accounts.
+
+Positional arguments:
+    NAME  name of the account"#;
+const SET_ACCOUNT_HELP: &str = r#"simpiwallet setaccount NAME
+
+Make the given account the active account.
+The first account of each wallet is called "default".
+
+Positional arguments:
+    NAME  name of the account"#;
+const FUND_HELP: &str = r#"simpiwallet fund AMOUNT [--blocks N]
+
+Send coins from the wallet of the node to a new address of this wallet.
+Only works on regtest.
+
+Positional arguments:
+    AMOUNT  amount with unit, such as "1btc"
+
+Options:
+    --blocks N  number of blocks to mine afterwards (default: 1)"#;
+const FORGET_UTXO_HELP: &str = r#"simpiwallet forgetutxo TXID:VOUT [--undo]
+
+Exclude a UTXO from balances and coin selection,
+for instance because it cannot be spent.
 
 Positional arguments:
-    PROGRAM  path to program in human encoding"#;
-const SATISFY_PROGRAM_HELP: &str = r#"simpiwallet satisfyprogram PROGRAM WITNESS
+    TXID:VOUT  outpoint of the UTXO
+
+Options:
+    --undo  include the UTXO again"#;
+const IMPORT_PROGRAM_HELP: &str = r#"simpiwallet importprogram PROGRAM [--verbose]
+
+Positional arguments:
+    PROGRAM  path to program in human encoding, or "-" to read it from stdin
+
+Options:
+    -v, --verbose  print the type, node count and witness names of the program"#;
+const IMPORT_CMR_HELP: &str = r#"simpiwallet importcmr CMR
+
+Positional arguments:
+    CMR  commitment Merkle root of program in hex"#;
+const SATISFY_PROGRAM_HELP: &str = r#"simpiwallet satisfyprogram PROGRAM WITNESS [PROGRAM WITNESS ...] [--verify [--locktime N] [--sequence N]]
 
 Positional arguments:
     PROGRAM  path to program in human encoding
-    WITNESS  path to witness data in JSON encoding"#;
+    WITNESS  path to witness data in JSON encoding
+
+Options:
+    --verify      ask the node to run the program in a trial spend of a UTXO of the fragment,
+                  and keep the previous satisfaction if the program fails
+    --locktime N  set the lock time of the trial spend to N
+    --sequence N  set the sequence of the trial input to N
+
+Without a UTXO, or if the node rejects the trial for another reason than the program,
+such as a lock time in the future, the new satisfaction is kept but reported as not verified.
+
+One path can be "-" to read from stdin.
+Pass several pairs to satisfy several fragments at once. Each pair succeeds or fails on its own,
+and the exit code is 1 if any pair failed.
+
+Witness values are hex strings of bytes by default.
+Structured values are objects: {"unit": null}, {"left": VALUE}, {"right": VALUE}, {"pair": [VALUE, VALUE]}"#;
+const DECODE_SATISFACTION_HELP: &str = r#"simpiwallet decodesatisfaction [CMR | --base64 SATISFACTION]
+
+Positional arguments:
+    CMR  commitment Merkle root of an imported program in hex
+
+Options:
+    --base64 SATISFACTION  decode the given satisfaction instead of a stored one"#;
+const SPEND_ASSEMBLY_HELP: &str = r#"simpiwallet spendassembly CMR ADDRESS [--yes] [--force] [--no-check] [--hex] [--save-hex PATH] [--locktime N] [--sequence N]
+
+Send a UTXO of an assembly fragment to ADDRESS, paying the fee from the UTXO.
+
+Positional arguments:
+    CMR      commitment Merkle root of the fragment in hex
+    ADDRESS  address that receives the funds
+
+Options:
+    -y, --yes         broadcast without asking for confirmation
+    --force           send even if the fee is unreasonably high
+    --no-check        broadcast without asking the node if it would accept the transaction
+    --hex             also print the signed transaction in hex
+    --save-hex PATH   write the signed transaction in hex to PATH
+    --locktime N      set the lock time of the transaction to N (block height or timestamp)
+    --sequence N      set the sequence of the input to N (see importprogram --verbose)"#;
 const HELP_HELP: &str =
-    "simpiwallet help [new | getnewaddress | getbalance | sendtoaddress | setfee | setrpc | setnetwork | importprogram | satisfyprogram]";
+    "simpiwallet help [new | importkey | backup | import | getnewaddress | getbalance | listunspent | sendtoaddress | sendtoscript | speedup | consolidate | setfee | setrpc | setnetwork | refreshchainparams | info | getconfig | checkwallet | abortscan | ismine | deriveaddress | signrawtransaction | issueasset | newaccount | setaccount | fund | forgetutxo | importprogram | importcmr | satisfyprogram | decodesatisfaction | spendassembly]";
+
+/// Parse the command line.
+///
+/// The options are returned even if parsing fails,
+/// so errors can be reported as JSON once `--json` has been seen.
+pub fn command() -> (Options, Result<Command, Error>) {
+    let mut options = Options::default();
+    let command = parse_command(&mut options);
+    (options, command)
+}
 
-pub fn command() -> Result<Command, Error> {
+fn parse_command(options: &mut Options) -> Result<Command, Error> {
     let mut parser = lexopt::Parser::from_env();
-    let arg = parser.next()?.ok_or(Error::missing_value("subcommand"))?;
-
-    match arg {
-        Value(command) => {
-            let command = command.string()?;
-            match command.as_str() {
-                "new" => Ok(Command::New),
-                "getnewaddress" => Ok(Command::GetNewAddress),
-                "getbalance" => Ok(Command::GetBalance),
-                "sendtoaddress" => {
-                    let address = argument(&mut parser, "address")?;
-                    let amount = argument(&mut parser, "amount")?;
-                    let send_to = Payment { address, amount };
-                    Ok(Command::SendToAddress { send_to })
-                }
-                "setfee" => {
-                    let fee = argument(&mut parser, "amount")?;
-                    Ok(Command::SetFee { fee })
-                }
-                "setrpc" => {
-                    let url = argument(&mut parser, "url")?;
-                    let user = argument(&mut parser, "user")?;
-                    let pass = optional_argument(&mut parser)?;
-                    let rpc = Connection { url, user, pass };
-                    Ok(Command::SetRpc { rpc })
-                }
-                "setnetwork" => {
-                    let network = argument(&mut parser, "network")?;
-                    Ok(Command::SetNetwork { network })
-                }
-                "importprogram" => {
-                    let program = argument(&mut parser, "program")?;
-                    Ok(Command::ImportProgram { program })
-                }
-                "satisfyprogram" => {
-                    let program = argument(&mut parser, "program")?;
-                    let witness = argument(&mut parser, "witness")?;
-                    Ok(Command::SatisfyProgram { program, witness })
-                }
-                "help" => {
-                    let help = match optional_argument::<String>(&mut parser)?.as_deref() {
-                        Some("new") => NEW_HELP,
-                        Some("getnewaddress") => GET_NEW_ADDRESS_HELP,
-                        Some("getbalance") => GET_BALANCE_HELP,
-                        Some("sendtoaddress") => SEND_TO_ADDRESS_HELP,
-                        Some("setfee") => SET_FEE_HELP,
-                        Some("setrpc") => SET_RPC_HELP,
-                        Some("setnetwork") => SET_NETWORK_HELP,
-                        Some("importprogram") => IMPORT_PROGRAM_HELP,
-                        Some("satisfyprogram") => SATISFY_PROGRAM_HELP,
-                        Some("help") => HELP_HELP,
-                        _ => HELP,
-                    };
-
-                    println!("{}", help);
-                    std::process::exit(0);
-                }
-                command => Err(Error::unknown_command(command)),
-            }
-        }
-        Long("help") => {
-            println!("{}", HELP);
+
+    loop {
+        let arg = parser.next()?.ok_or(Error::missing_value("subcommand"))?;
+
+        match arg {
+            Value(command) => {
+                return subcommand(&mut parser, command.string()?.as_str(), options);
+            }
+            Long("wallet") => {
+                options.wallet = parser.value()?.into();
+            }
+            Long("config") => {
+                options.config = parser.value()?.into();
+            }
+            Long("fee") => {
+                options.overrides.fee = Some(amount(&parser.value()?.string()?)?);
+            }
+            Long("network") => {
+                options.overrides.network = Some(value(parser)?);
+            }
+            Long("refresh") => {
+                options.overrides.scan_cache_ttl = Some(0);
+            }
+            Long("json") => {
+                options.json = true;
+            }
+            Short('y') | Long("yes") => {
+                set_non_interactive();
+            }
+            Long("debug-rpc") => {
+                options.debug_rpc = true;
+            }
+            Long("sat") => {
+                options.sat = true;
+            }
+            Long("deterministic-nonces") => {
+                options.deterministic_nonces = true;
+            }
+            Long("help") => {
+                println!("{}", HELP);
+                std::process::exit(0);
+            }
+            _ => return Err(arg.unexpected().into()),
+        }
+    }
+}
+
+fn subcommand(
+    parser: &mut lexopt::Parser,
+    command: &str,
+    global: &mut Options,
+) -> Result<Command, Error> {
+    match command {
+        "new" => {
+            let mut random_internal_key = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("random-internal-key") => random_internal_key = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::New {
+                random_internal_key,
+            })
+        }
+        "backup" => {
+            let path = argument(parser, "path")?;
+            Ok(Command::Backup { path })
+        }
+        "import" => {
+            let path = argument(parser, "path")?;
+            Ok(Command::Import { path })
+        }
+        "importkey" => {
+            let mut random_internal_key = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("random-internal-key") => random_internal_key = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::ImportKey {
+                random_internal_key,
+            })
+        }
+        "getnewaddress" => {
+            let mut count = None;
+            let mut skip_used = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    // Same as the global option
+                    Long("json") => global.json = true,
+                    Long("skip-used") => skip_used = true,
+                    // Coins sent to a confidential address would be invisible to the wallet
+                    Long("confidential") => {
+                        return Err(Error::CouldNotParse(
+                            "--confidential is not supported until the wallet can unblind received coins"
+                                .to_string(),
+                        ))
+                    }
+                    Long("count") => {
+                        let n: u32 = value(parser)?;
+                        if n == 0 {
+                            return Err(Error::CouldNotParse(
+                                "count must be at least 1".to_string(),
+                            ));
+                        }
+                        count = Some(n);
+                    }
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::GetNewAddress { count, skip_used })
+        }
+        "getbalance" => {
+            let mut from_height = None;
+            let mut since_last_scan = false;
+            let mut by_address = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("from-height") => from_height = Some(value(parser)?),
+                    Long("since-last-scan") => since_last_scan = true,
+                    Long("by-address") => by_address = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::GetBalance {
+                from_height,
+                since_last_scan,
+                by_address,
+            })
+        }
+        "listunspent" => {
+            let mut order = UtxoOrder::default();
+            let mut offset = 0;
+            let mut limit = None;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("sort") => order = value(parser)?,
+                    Long("offset") => offset = value(parser)?,
+                    Long("limit") => limit = Some(value(parser)?),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::ListUnspent {
+                order,
+                offset,
+                limit,
+            })
+        }
+        "sendtoaddress" | "sendtoscript" => {
+            let mut recipient = None;
+            let mut amount = None;
+            let mut from_file = None;
+            let mut options = SendOptions::default();
+            let mut yes = false;
+            let mut plan = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Value(s) if from_file.is_none() && recipient.is_none() => {
+                        let s = s.string()?;
+                        recipient = Some(match command {
+                            "sendtoscript" => {
+                                let bytes = Vec::<u8>::from_hex(&s)
+                                    .map_err(|e| Error::CouldNotParse(e.to_string()))?;
+                                Recipient::Script(elements::Script::from(bytes))
+                            }
+                            _ => Recipient::Address(s.parse()?),
+                        });
+                    }
+                    Value(s) if from_file.is_none() && amount.is_none() => {
+                        amount = Some(s.string()?.parse()?)
+                    }
+                    Long("from-file") if command == "sendtoaddress" && recipient.is_none() => {
+                        from_file = Some(parser.value()?.into())
+                    }
+                    Short('y') | Long("yes") => yes = true,
+                    Long("force") => options.force = true,
+                    Long("no-check") => options.no_check = true,
+                    Long("hex") => options.print_hex = true,
+                    Long("save-hex") => options.save_hex = Some(parser.value()?.into()),
+                    Long("change-address") => options.change_address = Some(value(parser)?),
+                    Long("data") => {
+                        let hex: String = value(parser)?;
+                        let data = Vec::<u8>::from_hex(&hex)
+                            .map_err(|e| Error::CouldNotParse(e.to_string()))?;
+                        options.data = Some(data);
+                    }
+                    Long("coins") => options.coins = coin_source(parser)?,
+                    Long("skip-mempool-spends") => options.skip_mempool_spends = true,
+                    Long("plan") => plan = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            if let Some(path) = from_file {
+                if plan {
+                    return Err(Error::CouldNotParse(
+                        "--plan cannot be combined with --from-file".to_string(),
+                    ));
+                }
+                // Stdin is used up by the request, so the confirmation would read nothing
+                if path == Path::new("-") && !yes && !is_non_interactive() {
+                    return Err(Error::CouldNotParse(
+                        "reading the request from stdin requires --yes".to_string(),
+                    ));
+                }
+                return Ok(Command::SendFromFile { path, options, yes });
+            }
+            let recipient = recipient.ok_or(Error::missing_value("recipient"))?;
+            let amount = amount.ok_or(Error::missing_value("amount"))?;
+            if plan {
+                return Ok(Command::PlanSend {
+                    recipient,
+                    amount,
+                    options,
+                });
+            }
+            Ok(Command::SendToAddress {
+                recipient,
+                amount,
+                options,
+                yes,
+            })
+        }
+        "speedup" => {
+            let txid = argument(parser, "txid")?;
+            let mut fee_rate = None;
+            let mut options = SendOptions::default();
+            let mut yes = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("fee-rate") => {
+                        let rate: f64 = value(parser)?;
+                        if !(rate.is_finite() && 0.0 < rate) {
+                            return Err(Error::CouldNotParse(
+                                "fee rate must be a positive number".to_string(),
+                            ));
+                        }
+                        fee_rate = Some(rate);
+                    }
+                    Short('y') | Long("yes") => yes = true,
+                    Long("force") => options.force = true,
+                    Long("no-check") => options.no_check = true,
+                    Long("hex") => options.print_hex = true,
+                    Long("save-hex") => options.save_hex = Some(parser.value()?.into()),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::SpeedUp {
+                txid,
+                fee_rate,
+                options,
+                yes,
+            })
+        }
+        "consolidate" => {
+            let mut max_inputs = DEFAULT_MAX_INPUTS;
+            let mut options = SendOptions::default();
+            let mut yes = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("max-inputs") => max_inputs = value(parser)?,
+                    Long("coins") => options.coins = coin_source(parser)?,
+                    Long("skip-mempool-spends") => options.skip_mempool_spends = true,
+                    Short('y') | Long("yes") => yes = true,
+                    Long("force") => options.force = true,
+                    Long("no-check") => options.no_check = true,
+                    Long("hex") => options.print_hex = true,
+                    Long("save-hex") => options.save_hex = Some(parser.value()?.into()),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+            if max_inputs == 0 {
+                return Err(Error::CouldNotParse(
+                    "max inputs must be at least 1".to_string(),
+                ));
+            }
+
+            Ok(Command::Consolidate {
+                max_inputs,
+                options,
+                yes,
+            })
+        }
+        "setfee" => {
+            let fee: String = argument(parser, "amount")?;
+            let fee = amount(&fee)?;
+            Ok(Command::SetFee { fee })
+        }
+        "setrpc" => {
+            let url = argument(parser, "url")?;
+            let user = argument(parser, "user")?;
+            let mut pass = None;
+            let mut force = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Value(value) if pass.is_none() => pass = Some(value.string()?),
+                    Long("force") => force = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            // Passwords on the command line end up in the shell history and the process list
+            let pass = match pass {
+                Some(pass) => Some(pass),
+                None => Some(prompt_password("RPC password (empty for none): ")?)
+                    .filter(|pass| !pass.is_empty()),
+            };
+            let rpc = Connection { url, user, pass };
+            Ok(Command::SetRpc { rpc, force })
+        }
+        "setnetwork" => {
+            let network = match argument::<String>(parser, "network")?.as_str() {
+                "custom" => {
+                    let bitcoin_id = argument(parser, "bitcoin id")?;
+                    let genesis_hash = argument(parser, "genesis hash")?;
+                    Network::Custom {
+                        bitcoin_id,
+                        genesis_hash,
+                        address_params: &elements::AddressParams::ELEMENTS,
+                    }
+                }
+                network => {
+                    Network::from_str(network).map_err(|e| Error::CouldNotParse(e.to_string()))?
+                }
+            };
+
+            let mut params = CustomAddressParams::from(network.address_params());
+            let mut custom_params = false;
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("p2pkh-prefix") => params.p2pkh_prefix = value(parser)?,
+                    Long("p2sh-prefix") => params.p2sh_prefix = value(parser)?,
+                    Long("blinded-prefix") => params.blinded_prefix = value(parser)?,
+                    Long("bech-hrp") => params.bech_hrp = value(parser)?,
+                    Long("blech-hrp") => params.blech_hrp = value(parser)?,
+                    _ => return Err(arg.unexpected().into()),
+                }
+                custom_params = true;
+            }
+
+            let network = if custom_params {
+                params.validate().map_err(Error::CouldNotParse)?;
+                network.with_address_params(params.leak())
+            } else {
+                network
+            };
+            Ok(Command::SetNetwork { network })
+        }
+        "refreshchainparams" => Ok(Command::RefreshChainParams),
+        "info" => Ok(Command::Info),
+        "getconfig" => Ok(Command::GetConfig),
+        "checkwallet" => Ok(Command::CheckWallet),
+        "abortscan" => Ok(Command::AbortScan),
+        "ismine" => {
+            let address = argument(parser, "address")?;
+            Ok(Command::IsMine { address })
+        }
+        "deriveaddress" => {
+            let index = argument(parser, "index")?;
+            Ok(Command::DeriveAddress { index })
+        }
+        "signrawtransaction" => {
+            let hex: String = argument(parser, "transaction")?;
+            let bytes =
+                Vec::<u8>::from_hex(&hex).map_err(|e| Error::CouldNotParse(e.to_string()))?;
+            let tx = elements::encode::deserialize(&bytes)
+                .map_err(|e| Error::CouldNotParse(e.to_string()))?;
+            let prevouts: String = argument(parser, "prevouts")?;
+            let prevouts = serde_json::from_str(&prevouts)?;
+            Ok(Command::SignRawTransaction { tx, prevouts })
+        }
+        "issueasset" => {
+            let amount = argument(parser, "amount")?;
+            let mut token_amount = 0;
+            let mut options = SendOptions::default();
+            let mut yes = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("token-amount") => token_amount = value(parser)?,
+                    Long("skip-mempool-spends") => options.skip_mempool_spends = true,
+                    Short('y') | Long("yes") => yes = true,
+                    Long("no-check") => options.no_check = true,
+                    Long("hex") => options.print_hex = true,
+                    Long("save-hex") => options.save_hex = Some(parser.value()?.into()),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            if amount == 0 {
+                return Err(Error::CouldNotParse(
+                    "amount must be at least 1".to_string(),
+                ));
+            }
+
+            Ok(Command::IssueAsset {
+                amount,
+                token_amount,
+                options,
+                yes,
+            })
+        }
+        "newaccount" => {
+            let name = argument(parser, "name")?;
+            Ok(Command::NewAccount { name })
+        }
+        "setaccount" => {
+            let name = argument(parser, "name")?;
+            Ok(Command::SetAccount { name })
+        }
+        "fund" => {
+            let amount: String = argument(parser, "amount")?;
+            let amount = self::amount(&amount)?;
+            let mut blocks = 1;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("blocks") => blocks = value(parser)?,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::Fund { amount, blocks })
+        }
+        "forgetutxo" => {
+            let outpoint = argument(parser, "outpoint")?;
+            let mut undo = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("undo") => undo = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::ForgetUtxo { outpoint, undo })
+        }
+        "importprogram" => {
+            let program = argument(parser, "program")?;
+            let mut verbose = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Short('v') | Long("verbose") => verbose = true,
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::ImportProgram { program, verbose })
+        }
+        "importcmr" => {
+            let cmr = cmr_argument(parser, "cmr")?;
+            Ok(Command::ImportCmr { cmr })
+        }
+        "satisfyprogram" => {
+            let mut paths = Vec::new();
+            let mut verify = false;
+            let mut options = SendOptions::default();
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("verify") => verify = true,
+                    Long("locktime") => {
+                        let locktime: u32 = value(parser)?;
+                        options.lock_time = Some(elements::LockTime::from_consensus(locktime));
+                    }
+                    Long("sequence") => {
+                        let sequence: u32 = value(parser)?;
+                        options.sequence = Some(elements::Sequence::from_consensus(sequence));
+                    }
+                    Value(path) => paths.push(PathBuf::from(path)),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+            if !verify && (options.lock_time.is_some() || options.sequence.is_some()) {
+                return Err(Error::CouldNotParse(
+                    "--locktime and --sequence only apply to --verify".to_string(),
+                ));
+            }
+
+            if paths.is_empty() {
+                return Err(Error::missing_value("program"));
+            }
+            if paths.len() % 2 != 0 {
+                return Err(Error::missing_value("witness"));
+            }
+            let pairs: Vec<_> = paths
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+
+            let stdin_count = pairs
+                .iter()
+                .flat_map(|(program, witness)| [program, witness])
+                .filter(|path| path.as_os_str() == "-")
+                .count();
+            if 1 < stdin_count {
+                return Err(Error::CouldNotParse(
+                    "only one path can be read from stdin".to_string(),
+                ));
+            }
+            Ok(Command::SatisfyProgram {
+                pairs,
+                verify,
+                options,
+            })
+        }
+        "decodesatisfaction" => {
+            let mut cmr = None;
+            let mut encoded = None;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Long("base64") => encoded = Some(value(parser)?),
+                    Value(hex) if cmr.is_none() => cmr = Some(parse_cmr(&hex.string()?)?),
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            let source = match (cmr, encoded) {
+                (Some(cmr), None) => SatisfactionSource::Stored(cmr),
+                (None, Some(encoded)) => SatisfactionSource::Encoded(encoded),
+                (None, None) => return Err(Error::missing_value("cmr")),
+                (Some(..), Some(..)) => {
+                    return Err(Error::CouldNotParse(
+                        "expected either CMR or --base64, not both".to_string(),
+                    ))
+                }
+            };
+            Ok(Command::DecodeSatisfaction { source })
+        }
+        "spendassembly" => {
+            let cmr = cmr_argument(parser, "cmr")?;
+            let address = argument(parser, "address")?;
+            let mut options = SendOptions::default();
+            let mut yes = false;
+
+            while let Some(arg) = parser.next()? {
+                match arg {
+                    Short('y') | Long("yes") => yes = true,
+                    Long("force") => options.force = true,
+                    Long("no-check") => options.no_check = true,
+                    Long("hex") => options.print_hex = true,
+                    Long("save-hex") => options.save_hex = Some(parser.value()?.into()),
+                    Long("locktime") => {
+                        let locktime: u32 = value(parser)?;
+                        options.lock_time = Some(elements::LockTime::from_consensus(locktime));
+                    }
+                    Long("sequence") => {
+                        let sequence: u32 = value(parser)?;
+                        options.sequence = Some(elements::Sequence::from_consensus(sequence));
+                    }
+                    _ => return Err(arg.unexpected().into()),
+                }
+            }
+
+            Ok(Command::SpendAssembly {
+                cmr,
+                address,
+                options,
+                yes,
+            })
+        }
+        "help" => {
+            let help = match optional_argument::<String>(parser)?.as_deref() {
+                Some("new") => NEW_HELP,
+                Some("importkey") => IMPORT_KEY_HELP,
+                Some("backup") => BACKUP_HELP,
+                Some("import") => IMPORT_HELP,
+                Some("getnewaddress") => GET_NEW_ADDRESS_HELP,
+                Some("getbalance") => GET_BALANCE_HELP,
+                Some("listunspent") => LIST_UNSPENT_HELP,
+                Some("sendtoaddress") => SEND_TO_ADDRESS_HELP,
+                Some("sendtoscript") => SEND_TO_SCRIPT_HELP,
+                Some("speedup") => SPEED_UP_HELP,
+                Some("consolidate") => CONSOLIDATE_HELP,
+                Some("setfee") => SET_FEE_HELP,
+                Some("setrpc") => SET_RPC_HELP,
+                Some("setnetwork") => SET_NETWORK_HELP,
+                Some("refreshchainparams") => REFRESH_CHAIN_PARAMS_HELP,
+                Some("info") => INFO_HELP,
+                Some("getconfig") => GET_CONFIG_HELP,
+                Some("checkwallet") => CHECK_WALLET_HELP,
+                Some("abortscan") => ABORT_SCAN_HELP,
+                Some("ismine") => IS_MINE_HELP,
+                Some("deriveaddress") => DERIVE_ADDRESS_HELP,
+                Some("signrawtransaction") => SIGN_RAW_TRANSACTION_HELP,
+                Some("issueasset") => ISSUE_ASSET_HELP,
+                Some("newaccount") => NEW_ACCOUNT_HELP,
+                Some("setaccount") => SET_ACCOUNT_HELP,
+                Some("fund") => FUND_HELP,
+                Some("forgetutxo") => FORGET_UTXO_HELP,
+                Some("importprogram") => IMPORT_PROGRAM_HELP,
+                Some("importcmr") => IMPORT_CMR_HELP,
+                Some("satisfyprogram") => SATISFY_PROGRAM_HELP,
+                Some("decodesatisfaction") => DECODE_SATISFACTION_HELP,
+                Some("spendassembly") => SPEND_ASSEMBLY_HELP,
+                Some("help") => HELP_HELP,
+                _ => HELP,
+            };
+
+            println!("{}", help);
             std::process::exit(0);
         }
-        _ => Err(arg.unexpected().into()),
+        command => Err(Error::unknown_command(command)),
+    }
+}
+
+fn coin_source(parser: &mut lexopt::Parser) -> Result<CoinSource, Error> {
+    let name: String = value(parser)?;
+    match name.as_str() {
+        "any" => Ok(CoinSource::Any),
+        "keys" => Ok(CoinSource::Keys),
+        "assembly" => Ok(CoinSource::Assembly),
+        _ => Err(Error::CouldNotParse(format!(
+            "unknown kind of coins: {}",
+            name
+        ))),
+    }
+}
+
+fn cmr_argument(parser: &mut lexopt::Parser, name: &str) -> Result<simplicity::Cmr, Error> {
+    let hex: String = argument(parser, name)?;
+    parse_cmr(&hex)
+}
+
+fn parse_cmr(hex: &str) -> Result<simplicity::Cmr, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|e| Error::CouldNotParse(e.to_string()))?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| Error::CouldNotParse("CMR must be 32 bytes long".to_string()))?;
+    Ok(simplicity::Cmr::from_byte_array(bytes))
+}
+
+/// Parse an amount with an explicit unit, such as `0.5btc`, `0.5 BTC` or `50000sat`.
+///
+/// Amounts without unit are rejected, so bitcoin and satoshi cannot be confused.
+pub fn amount(s: &str) -> Result<bitcoin::Amount, Error> {
+    let s = s.trim();
+    if s.starts_with('-') {
+        return Err(Error::CouldNotParse(format!(
+            "amount must not be negative: {}",
+            s
+        )));
     }
+    let unit_start = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .ok_or_else(|| Error::CouldNotParse(format!("amount needs a unit (btc or sat): {}", s)))?;
+    let (number, unit) = s.split_at(unit_start);
+    let denomination = match unit.trim().to_lowercase().as_str() {
+        "btc" => bitcoin::amount::Denomination::Bitcoin,
+        "sat" | "sats" => bitcoin::amount::Denomination::Satoshi,
+        unit => {
+            return Err(Error::CouldNotParse(format!(
+                "unsupported unit (use btc or sat): {}",
+                unit
+            )))
+        }
+    };
+    Ok(bitcoin::Amount::from_str_in(number, denomination)?)
+}
+
+/// Parse the value of an option such as `--fee AMOUNT`.
+fn value<A>(parser: &mut lexopt::Parser) -> Result<A, Error>
+where
+    A: FromStr,
+    <A as FromStr>::Err: ToString,
+{
+    let str = parser.value()?.string()?;
+    A::from_str(&str).map_err(|e| Error::CouldNotParse(e.to_string()))
 }
 
 fn argument<A>(parser: &mut lexopt::Parser, name: &str) -> Result<A, Error>
@@ -133,13 +1050,49 @@ where
     }
 }
 
+/// Whether prompts are answered without asking the user.
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Answer prompts without asking the user, see [`is_non_interactive`].
+pub fn set_non_interactive() {
+    NON_INTERACTIVE.store(true, Ordering::Relaxed);
+}
+
+/// Return whether prompts are answered without asking the user.
+///
+/// This is the case with the global `--yes`, or if the environment variable
+/// `CI` or `SIMPIWALLET_NONINTERACTIVE` is set to anything but an empty string, `0` or `false`.
+pub fn is_non_interactive() -> bool {
+    NON_INTERACTIVE.load(Ordering::Relaxed)
+        || ["CI", "SIMPIWALLET_NONINTERACTIVE"].iter().any(|name| {
+            std::env::var(name).is_ok_and(|value| !matches!(value.as_str(), "" | "0" | "false"))
+        })
+}
+
+/// Ask the user a yes-or-no question.
+///
+/// Return the default answer without asking if prompts are disabled.
+pub fn ask(message: &str, default: bool) -> Result<bool, Error> {
+    if is_non_interactive() {
+        return Ok(default);
+    }
+    prompt::<Choice>(message).map(bool::from)
+}
+
+/// Ask the user for a value.
+///
+/// Fail if prompts are disabled, because there is no sensible default.
 pub fn prompt<A>(message: &str) -> Result<A, Error>
 where
     A: FromStr,
     <A as FromStr>::Err: ToString,
 {
-    print!("{}", message);
-    std::io::stdout().flush()?;
+    if is_non_interactive() {
+        return Err(Error::InputRequired(message.trim_end().to_string()));
+    }
+    // Keep stdout free for the result of the command
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
 
     let mut input = String::new();
     std::io::stdin().read_line(&mut input)?;
@@ -150,6 +1103,62 @@ where
     }
 }
 
+/// Read a secret value from stdin without echoing it to the terminal.
+///
+/// Unlike prompts, this also works if prompts are disabled, so the secret can be piped in.
+/// The input is erased from memory after parsing.
+pub fn read_secret<A>(message: &str) -> Result<A, Error>
+where
+    A: FromStr,
+    <A as FromStr>::Err: ToString,
+{
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+
+    let mut input = if std::io::stdin().is_terminal() {
+        rpassword::read_password()?
+    } else {
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input)?;
+        input
+    };
+    let parsed = input.trim().parse::<A>();
+    zeroize::Zeroize::zeroize(&mut input);
+
+    parsed.map_err(|err| Error::CouldNotParse(err.to_string()))
+}
+
+/// Read a new passphrase with [`read_secret`].
+///
+/// On a terminal, the passphrase is asked twice to catch typos.
+pub fn read_new_passphrase(message: &str) -> Result<String, Error> {
+    let mut passphrase: String = read_secret(message)?;
+    if passphrase.is_empty() {
+        return Err(Error::CouldNotParse(
+            "passphrase must not be empty".to_string(),
+        ));
+    }
+    if std::io::stdin().is_terminal() {
+        let mut repeated: String = read_secret("Repeat passphrase: ")?;
+        let matches = passphrase == repeated;
+        zeroize::Zeroize::zeroize(&mut repeated);
+        if !matches {
+            zeroize::Zeroize::zeroize(&mut passphrase);
+            return Err(Error::CouldNotParse("passphrases do not match".to_string()));
+        }
+    }
+    Ok(passphrase)
+}
+
+/// Ask the user for a secret without echoing it to the terminal.
+pub fn prompt_password(message: &str) -> Result<String, Error> {
+    if is_non_interactive() {
+        return Err(Error::InputRequired(message.trim_end().to_string()));
+    }
+    let password = rpassword::prompt_password(message)?;
+    Ok(password)
+}
+
 pub struct Choice(bool);
 
 impl FromStr for Choice {
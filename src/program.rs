@@ -0,0 +1,197 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+use elements_miniscript::elements::hex::FromHex;
+use serde::{Deserialize, Serialize};
+use simplicity::dag::{DagLike, InternalSharing, NoSharing};
+use simplicity::human_encoding::NamedCommitNode;
+use simplicity::jet::Elements;
+use simplicity::node::Inner;
+use simplicity::Value;
+
+use crate::error::Error;
+
+/// Value of a witness node in the witness file.
+///
+/// A plain string is read as hex bytes.
+/// Other values are given as JSON objects, such as `{"unit": null}`,
+/// `{"left": VALUE}`, `{"right": VALUE}` or `{"pair": [VALUE, VALUE]}`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum WitnessValue {
+    Hex(String),
+    Structured(StructuredValue),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StructuredValue {
+    Unit,
+    Left(Box<WitnessValue>),
+    Right(Box<WitnessValue>),
+    Pair(Box<WitnessValue>, Box<WitnessValue>),
+}
+
+impl WitnessValue {
+    pub fn to_value(&self) -> Result<Arc<Value>, Error> {
+        match self {
+            WitnessValue::Hex(hex) => Vec::<u8>::from_hex(hex)
+                .map(|bytes| Value::from_slice(&bytes))
+                .map_err(|err| Error::CouldNotParse(err.to_string())),
+            WitnessValue::Structured(StructuredValue::Unit) => Ok(Value::unit()),
+            WitnessValue::Structured(StructuredValue::Left(inner)) => {
+                inner.to_value().map(Value::sum_l)
+            }
+            WitnessValue::Structured(StructuredValue::Right(inner)) => {
+                inner.to_value().map(Value::sum_r)
+            }
+            WitnessValue::Structured(StructuredValue::Pair(left, right)) => {
+                Ok(Value::prod(left.to_value()?, right.to_value()?))
+            }
+        }
+    }
+}
+
+/// Return the names of the witness nodes of the given program.
+pub fn witness_names(program: &NamedCommitNode<Elements>) -> BTreeSet<Arc<str>> {
+    program
+        .post_order_iter::<NoSharing>()
+        .filter(|data| matches!(data.node.inner(), Inner::Witness(..)))
+        .map(|data| Arc::clone(data.node.name()))
+        .collect()
+}
+
+/// Return the number of distinct nodes of the given program.
+pub fn node_count(program: &NamedCommitNode<Elements>) -> usize {
+    program.post_order_iter::<InternalSharing>().count()
+}
+
+/// Timelock that a program checks with one of the `check_lock_*` jets.
+///
+/// The value is known if the program feeds a constant into the jet.
+/// Otherwise it comes from a witness and is only known at spend time.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case", tag = "kind", content = "value")]
+pub enum Timelock {
+    /// Minimum block height of the lock time of the transaction
+    Height(Option<u32>),
+    /// Minimum timestamp of the lock time of the transaction
+    Time(Option<u32>),
+    /// Minimum number of blocks in the sequence of the input
+    Distance(Option<u16>),
+    /// Minimum number of 512-second intervals in the sequence of the input
+    Duration(Option<u16>),
+}
+
+impl fmt::Display for Timelock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (field, unit, value) = match self {
+            Timelock::Height(value) => ("lock time", "block height", value.map(u64::from)),
+            Timelock::Time(value) => ("lock time", "timestamp", value.map(u64::from)),
+            Timelock::Distance(value) => ("sequence", "blocks", value.map(u64::from)),
+            Timelock::Duration(value) => ("sequence", "512-second intervals", value.map(u64::from)),
+        };
+        match value {
+            Some(value) => write!(f, "{} of at least {} ({})", field, value, unit),
+            None => write!(f, "{} ({}) given by a witness", field, unit),
+        }
+    }
+}
+
+impl Timelock {
+    fn is_known(&self) -> bool {
+        match self {
+            Timelock::Height(value) | Timelock::Time(value) => value.is_some(),
+            Timelock::Distance(value) | Timelock::Duration(value) => value.is_some(),
+        }
+    }
+
+    fn same_kind(&self, other: &Self) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+}
+
+/// Return the bits of a value in order, if the value is made of bits only.
+fn value_bits(value: &Value, bits: &mut Vec<bool>) -> bool {
+    match value {
+        Value::SumL(inner) if matches!(**inner, Value::Unit) => bits.push(false),
+        Value::SumR(inner) if matches!(**inner, Value::Unit) => bits.push(true),
+        Value::Prod(left, right) => return value_bits(left, bits) && value_bits(right, bits),
+        _ => return false,
+    }
+    true
+}
+
+/// Return the value of a word as a big-endian integer.
+fn word_to_u64(value: &Value) -> Option<u64> {
+    let mut bits = Vec::new();
+    if !value_bits(value, &mut bits) || 64 < bits.len() {
+        return None;
+    }
+    Some(
+        bits.into_iter()
+            .fold(0, |acc, bit| (acc << 1) | u64::from(bit)),
+    )
+}
+
+/// Return the timelocks that the given program checks.
+///
+/// Timelocks of unreachable branches are included, too.
+pub fn timelocks(program: &NamedCommitNode<Elements>) -> BTreeSet<Timelock> {
+    let mut timelocks = BTreeSet::new();
+
+    for data in program.post_order_iter::<InternalSharing>() {
+        let (jet, value) = match data.node.inner() {
+            Inner::Jet(jet) => (*jet, None),
+            Inner::Comp(left, right) => match (left.inner(), right.inner()) {
+                (Inner::Word(value), Inner::Jet(jet)) => (*jet, word_to_u64(value)),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        let timelock = match jet {
+            Elements::CheckLockHeight => Timelock::Height(value.map(|v| v as u32)),
+            Elements::CheckLockTime => Timelock::Time(value.map(|v| v as u32)),
+            Elements::CheckLockDistance => Timelock::Distance(value.map(|v| v as u16)),
+            Elements::CheckLockDuration => Timelock::Duration(value.map(|v| v as u16)),
+            _ => continue,
+        };
+        timelocks.insert(timelock);
+    }
+
+    // A jet that is fed a constant is also visited on its own
+    let known: Vec<_> = timelocks
+        .iter()
+        .copied()
+        .filter(Timelock::is_known)
+        .collect();
+    timelocks
+        .retain(|timelock| timelock.is_known() || !known.iter().any(|k| k.same_kind(timelock)));
+    timelocks
+}
+
+/// Check that the given witness values match the witness nodes of the given program by name.
+pub fn check_witness_names(
+    program: &NamedCommitNode<Elements>,
+    values: &HashMap<Arc<str>, Arc<Value>>,
+) -> Result<(), Error> {
+    let expected = witness_names(program);
+    let mut unknown: Vec<String> = values
+        .keys()
+        .filter(|name| !expected.contains(*name))
+        .map(|name| name.to_string())
+        .collect();
+    unknown.sort();
+    let missing: Vec<String> = expected
+        .iter()
+        .filter(|name| !values.contains_key(*name))
+        .map(|name| name.to_string())
+        .collect();
+
+    if unknown.is_empty() && missing.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::WitnessMismatch { unknown, missing })
+    }
+}
@@ -1,16 +1,137 @@
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
 
 use bitcoin::key::PublicKey;
 use elements::bitcoin;
 use elements_miniscript as miniscript;
 use jsonrpc::simple_http::SimpleHttpTransport;
 use jsonrpc::{simple_http, Client};
-use miniscript::elements::hex::ToHex;
+use miniscript::elements::hex::{FromHex, ToHex};
 use miniscript::{elements, Descriptor};
 
 use crate::error::Error;
+use crate::output::DisplayAmount;
 use crate::state::{Utxo, UtxoSet};
 
+/// Number of descriptors per `scantxoutset` call, unless configured otherwise.
+pub const DEFAULT_SCAN_BATCH_SIZE: usize = 100;
+
+const SCAN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to wait for a scan of the UTXO set, which takes minutes on large chains.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const SCAN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const SCAN_RETRY_ATTEMPTS: usize = 6;
+
+/// Error code of `scantxoutset` when another scan is already running.
+const RPC_INVALID_PARAMETER: i32 = -8;
+
+fn is_scan_in_progress(error: &Error) -> bool {
+    match error {
+        Error::Rpc(jsonrpc::Error::Rpc(error)) => {
+            error.code == RPC_INVALID_PARAMETER
+                && error.message.starts_with("Scan already in progress")
+        }
+        _ => false,
+    }
+}
+
+/// Error code of `getrawtransaction` when the node doesn't know the transaction.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// Return whether the node doesn't know the requested transaction,
+/// for instance because it left the mempool after its id was listed.
+fn is_not_found(error: &Error) -> bool {
+    match error {
+        Error::Rpc(jsonrpc::Error::Rpc(error)) => error.code == RPC_INVALID_ADDRESS_OR_KEY,
+        _ => false,
+    }
+}
+
+/// Error code of `sendrawtransaction` when the transaction is already in the chain.
+const RPC_VERIFY_ALREADY_IN_CHAIN: i32 = -27;
+/// Error code of `sendrawtransaction` when the node rejects the transaction.
+const RPC_VERIFY_REJECTED: i32 = -26;
+
+/// Return whether the node already has the transaction that was sent,
+/// for instance because an earlier attempt timed out after it reached the node.
+fn is_already_known(error: &Error) -> bool {
+    match error {
+        Error::Rpc(jsonrpc::Error::Rpc(error)) => {
+            error.code == RPC_VERIFY_ALREADY_IN_CHAIN
+                || (error.code == RPC_VERIFY_REJECTED
+                    && (error.message.starts_with("txn-already-in-mempool")
+                        || error.message.starts_with("txn-already-known")))
+        }
+        _ => false,
+    }
+}
+
+/// Whether to print each request and raw response to stderr.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Print each request and raw response to stderr, for diagnosing problems with a node.
+///
+/// Credentials are part of the HTTP transport, so they are never printed.
+pub fn enable_debug() {
+    DEBUG.store(true, Ordering::Relaxed);
+}
+
+fn send(client: &Client, request: jsonrpc::Request) -> Result<jsonrpc::Response, Error> {
+    let debug = DEBUG.load(Ordering::Relaxed);
+    if debug {
+        let json = serde_json::to_string(&request).unwrap_or_else(|e| e.to_string());
+        eprintln!("RPC request: {}", json);
+    }
+    let response = client.send_request(request);
+    if debug {
+        match &response {
+            Ok(response) => match (&response.result, &response.error) {
+                (_, Some(error)) => eprintln!("RPC error: {:?}", error),
+                (Some(result), None) => eprintln!("RPC response: {}", result.get()),
+                (None, None) => eprintln!("RPC response: null"),
+            },
+            Err(error) => eprintln!("RPC failed: {}", error),
+        }
+    }
+    response.map_err(Error::from)
+}
+
+/// Send several requests at once, see [`send`].
+fn send_batch(
+    client: &Client,
+    requests: &[jsonrpc::Request],
+) -> Result<Vec<Option<jsonrpc::Response>>, Error> {
+    let debug = DEBUG.load(Ordering::Relaxed);
+    if debug {
+        let json = serde_json::to_string(requests).unwrap_or_else(|e| e.to_string());
+        eprintln!("RPC batch request: {}", json);
+    }
+    let responses = client.send_batch(requests);
+    if debug {
+        match &responses {
+            Ok(responses) => {
+                let json = serde_json::to_string(responses).unwrap_or_else(|e| e.to_string());
+                eprintln!("RPC batch response: {}", json);
+            }
+            Err(error) => eprintln!("RPC failed: {}", error),
+        }
+    }
+    responses.map_err(Error::from)
+}
+
+/// Decode a transaction from its consensus encoding in hex.
+fn decode_tx(hex: &str) -> Result<elements::Transaction, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|e| Error::CouldNotParse(e.to_string()))?;
+    elements::encode::deserialize(&bytes).map_err(|e| Error::CouldNotParse(e.to_string()))
+}
+
+/// Return the consensus encoding of the transaction in hex.
+pub fn tx_hex(tx: &elements::Transaction) -> String {
+    elements::pset::serialize::Serialize::serialize(tx).to_hex()
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Connection {
     pub url: String,
@@ -39,12 +160,29 @@ struct ScanTxOutResult {
     pub unspents: Vec<Unspents>,
 }
 
+/// Block at the tip of the chain when the UTXO set was scanned.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    pub hash: elements::BlockHash,
+    pub height: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+struct ScanTxOutStatus {
+    pub progress: f64,
+}
+
+/// Unspent output as returned by `scantxoutset`.
+///
+/// The node only reports the amount and asset of explicit outputs.
+/// Confidential outputs come with commitments instead.
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 struct Unspents {
-    #[serde(with = "bitcoin::amount::serde::as_btc")]
-    pub amount: bitcoin::amount::Amount,
-    pub asset: elements::AssetId,
+    #[serde(default, with = "bitcoin::amount::serde::as_btc::opt")]
+    pub amount: Option<bitcoin::amount::Amount>,
+    #[serde(default)]
+    pub asset: Option<elements::AssetId>,
     pub desc: String,
     pub height: u64,
     pub script_pub_key: elements::Script,
@@ -52,6 +190,81 @@ struct Unspents {
     pub vout: u32,
 }
 
+impl Unspents {
+    /// Check if the output belongs to the queried `raw(SCRIPT)` descriptor with the given script.
+    ///
+    /// Fall back to the descriptor string that the node echoes,
+    /// in case the reported script pubkey differs in encoding.
+    fn matches(&self, script_pubkey: &elements::Script) -> bool {
+        if &self.script_pub_key == script_pubkey {
+            return true;
+        }
+        let desc = self.desc.split('#').next().unwrap_or_default();
+        desc == format!("raw({})", script_pubkey.as_bytes().to_hex())
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct MempoolAcceptResult {
+    pub allowed: bool,
+    #[serde(default)]
+    pub reject_reason: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct BlockchainInfo {
+    pub chain: String,
+    pub blocks: u64,
+}
+
+/// Unspent output as returned by `gettxout`.
+///
+/// The node only reports the amount and asset of explicit outputs.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct TxOutInfo {
+    /// Number of confirmations, which is zero for outputs in the mempool
+    pub confirmations: u64,
+    #[serde(default, with = "bitcoin::amount::serde::as_btc::opt")]
+    pub value: Option<bitcoin::Amount>,
+    #[serde(default)]
+    pub asset: Option<elements::AssetId>,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: ScriptPubKeyInfo,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ScriptPubKeyInfo {
+    pub hex: elements::Script,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct MempoolInfo {
+    /// Minimum fee rate in BTC/kvB for a transaction to enter the mempool
+    pub mempoolminfee: f64,
+    /// Minimum fee rate in BTC/kvB for a transaction to be relayed
+    pub minrelaytxfee: f64,
+}
+
+impl MempoolInfo {
+    /// Return the smallest fee rate in sat/vB that the node accepts.
+    pub fn min_fee_rate(&self) -> f64 {
+        self.mempoolminfee.max(self.minrelaytxfee) * 100_000_000.0 / 1000.0
+    }
+
+    /// Return the smallest fee that the node accepts for a transaction of the given virtual size.
+    pub fn min_fee(&self, vsize: usize) -> bitcoin::Amount {
+        let btc_per_kvb = self.mempoolminfee.max(self.minrelaytxfee);
+        let sat_per_kvb = (btc_per_kvb * 100_000_000.0).round() as u64;
+        bitcoin::Amount::from_sat((sat_per_kvb * vsize as u64).div_ceil(1000))
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SidechainInfo {
+    pub pegged_asset: elements::AssetId,
+}
+
 impl Default for Connection {
     fn default() -> Self {
         Self {
@@ -72,6 +285,18 @@ impl Connection {
         Ok(Client::with_transport(t))
     }
 
+    /// Return a client that waits up to [`SCAN_TIMEOUT`] for the answer,
+    /// because the node only answers `scantxoutset start` once the scan is done.
+    fn scan_client(&self) -> Result<Client, simple_http::Error> {
+        let t = SimpleHttpTransport::builder()
+            .url(&self.url)?
+            .auth(&self.user, self.pass.as_ref())
+            .timeout(SCAN_TIMEOUT)
+            .build();
+
+        Ok(Client::with_transport(t))
+    }
+
     fn scantxoutset(
         &self,
         descriptors: &[Descriptor<PublicKey>],
@@ -88,46 +313,365 @@ impl Connection {
 
         let parameters = [jsonrpc::arg(action), jsonrpc::arg(descriptors)];
 
+        let client = self.scan_client()?;
+        let request = client.build_request("scantxoutset", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Return the progress of the running scan, if any.
+    fn scantxoutset_status(&self) -> Result<Option<ScanTxOutStatus>, Error> {
+        let action = serde_json::Value::String("status".to_string());
+        let parameters = [jsonrpc::arg(action)];
+
         let client = self.client()?;
         let request = client.build_request("scantxoutset", &parameters);
-        let response = client.send_request(request)?;
+        let response = send(&client, request)?;
 
         response.result().map_err(|e| e.into())
     }
 
-    pub fn scan(&self, descriptors: &[Descriptor<PublicKey>]) -> Result<UtxoSet, Error> {
-        let result = self.scantxoutset(descriptors)?;
+    /// Abort the running scan, which may have been started by another process.
+    ///
+    /// Return `false` if no scan was running.
+    pub fn scantxoutset_abort(&self) -> Result<bool, Error> {
+        let action = serde_json::Value::String("abort".to_string());
+        let parameters = [jsonrpc::arg(action)];
+
+        let client = self.client()?;
+        let request = client.build_request("scantxoutset", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Scan the UTXO set while printing the progress to stderr.
+    fn scantxoutset_with_progress(
+        &self,
+        descriptors: &[Descriptor<PublicKey>],
+    ) -> Result<ScanTxOutResult, Error> {
+        // Dropping the sender tells the polling thread that the scan finished
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                let mut printed = false;
+                while let Err(mpsc::RecvTimeoutError::Timeout) =
+                    receiver.recv_timeout(SCAN_PROGRESS_INTERVAL)
+                {
+                    if let Ok(Some(status)) = self.scantxoutset_status() {
+                        eprint!("\rScanning UTXO set: {:.0}%", status.progress);
+                        printed = true;
+                    }
+                }
+                if printed {
+                    eprintln!();
+                }
+            });
+
+            let result = self.scantxoutset(descriptors);
+            drop(sender);
+            result
+        })
+    }
+
+    /// Scan the UTXO set for the given descriptors.
+    ///
+    /// Descriptors are scanned in batches of `batch_size` to stay within the limits of the node.
+    ///
+    /// Also return the chain tip of the last batch, if there were any descriptors.
+    pub fn scan(
+        &self,
+        descriptors: &[Descriptor<PublicKey>],
+        batch_size: usize,
+        bitcoin_id: elements::AssetId,
+    ) -> Result<(UtxoSet, Option<ChainTip>), Error> {
+        let mut utxos = Vec::new();
+        let mut tip = None;
+        for batch in descriptors.chunks(batch_size.max(1)) {
+            let (batch_utxos, batch_tip) = self.scan_batch(batch, bitcoin_id)?;
+            utxos.extend(batch_utxos.0);
+            tip = Some(batch_tip);
+        }
+        Ok((UtxoSet(utxos), tip))
+    }
+
+    fn scan_batch(
+        &self,
+        descriptors: &[Descriptor<PublicKey>],
+        bitcoin_id: elements::AssetId,
+    ) -> Result<(UtxoSet, ChainTip), Error> {
+        // Only one scan can run at a time: wait for concurrent scans to finish
+        let mut attempts = 0;
+        let result = loop {
+            match self.scantxoutset_with_progress(descriptors) {
+                Err(error) if is_scan_in_progress(&error) => {
+                    if attempts == SCAN_RETRY_ATTEMPTS {
+                        return Err(Error::ScanInProgress);
+                    }
+                    attempts += 1;
+                    eprintln!(
+                        "Another UTXO set scan is in progress, retrying in {} seconds",
+                        SCAN_RETRY_DELAY.as_secs()
+                    );
+                    std::thread::sleep(SCAN_RETRY_DELAY);
+                }
+                result => break result?,
+            }
+        };
+        if !result.success {
+            return Err(Error::ScanAborted);
+        }
         let mut utxos = Vec::new();
 
         for unspent in result.unspents {
+            let (amount, asset) = match (unspent.amount, unspent.asset) {
+                (Some(amount), Some(asset)) => (amount, asset),
+                _ => {
+                    // Treating blinded coins as explicit would produce invalid signatures
+                    eprintln!(
+                        "Warning: skipping confidential output {}:{} because the wallet cannot unblind it",
+                        unspent.txid, unspent.vout
+                    );
+                    continue;
+                }
+            };
             let descriptor = descriptors
                 .iter()
-                .find(|desc| desc.script_pubkey() == unspent.script_pub_key)
-                .expect("Output script_pubkey was queried for")
+                .find(|desc| unspent.matches(&desc.script_pubkey()))
+                .ok_or_else(|| Error::UnknownScanResult(unspent.script_pub_key.clone()))?
                 .clone();
             let utxo = Utxo {
                 descriptor,
-                amount: unspent.amount,
+                amount,
+                asset,
                 outpoint: elements::OutPoint {
                     txid: unspent.txid,
                     vout: unspent.vout,
                 },
+                height: unspent.height,
             };
             utxos.push(utxo);
         }
 
-        Ok(UtxoSet(utxos))
+        // The node sums up the explicit bitcoin outputs on its own:
+        // a different sum means that outputs were missed or counted twice
+        let total: bitcoin::Amount = utxos
+            .iter()
+            .filter(|utxo| utxo.asset == bitcoin_id)
+            .map(|utxo| utxo.amount)
+            .sum();
+        if total != result.total_unblinded_bitcoin_amount {
+            eprintln!(
+                "Warning: the node counts {} of bitcoin in the scanned UTXOs, but the wallet counts {}; balances may be wrong",
+                DisplayAmount(result.total_unblinded_bitcoin_amount),
+                DisplayAmount(total)
+            );
+        }
+
+        let tip = ChainTip {
+            hash: result.bestblock,
+            height: result.height,
+        };
+        Ok((UtxoSet(utxos), tip))
+    }
+
+    pub fn getblockcount(&self) -> Result<u64, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getblockcount", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn getblockhash(&self, height: u64) -> Result<elements::BlockHash, Error> {
+        let parameters = [jsonrpc::arg(height)];
+
+        let client = self.client()?;
+        let request = client.build_request("getblockhash", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn getblockchaininfo(&self) -> Result<BlockchainInfo, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getblockchaininfo", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Return the unspent output at the given outpoint, or `None` if it is spent or unknown.
+    pub fn gettxout(&self, outpoint: &elements::OutPoint) -> Result<Option<TxOutInfo>, Error> {
+        let parameters = [jsonrpc::arg(outpoint.txid), jsonrpc::arg(outpoint.vout)];
+
+        let client = self.client()?;
+        let request = client.build_request("gettxout", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn getmempoolinfo(&self) -> Result<MempoolInfo, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getmempoolinfo", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn getsidechaininfo(&self) -> Result<SidechainInfo, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getsidechaininfo", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Send coins from the wallet of the node to the given address.
+    pub fn sendtoaddress(
+        &self,
+        address: &elements::Address,
+        amount: bitcoin::Amount,
+    ) -> Result<elements::Txid, Error> {
+        let parameters = [
+            jsonrpc::arg(address.to_string()),
+            jsonrpc::arg(amount.to_btc()),
+        ];
+
+        let client = self.client()?;
+        let request = client.build_request("sendtoaddress", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Return a new address of the wallet of the node.
+    pub fn getnewaddress(&self) -> Result<String, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getnewaddress", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn generatetoaddress(
+        &self,
+        blocks: u32,
+        address: &str,
+    ) -> Result<Vec<elements::BlockHash>, Error> {
+        let parameters = [jsonrpc::arg(blocks), jsonrpc::arg(address)];
+
+        let client = self.client()?;
+        let request = client.build_request("generatetoaddress", &parameters);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    /// Check if the node would accept the transaction into its mempool, without broadcasting it.
+    ///
+    /// Transactions whose fee rate exceeds the maximum in BTC/kvB are rejected.
+    /// Without a maximum, the node applies its default. A maximum of zero disables the check.
+    pub fn testmempoolaccept(
+        &self,
+        tx: &elements::Transaction,
+        max_fee_rate: Option<f64>,
+    ) -> Result<(), Error> {
+        let transactions = serde_json::Value::Array(vec![serde_json::Value::String(tx_hex(tx))]);
+        let mut parameters = vec![jsonrpc::arg(transactions)];
+        if let Some(max_fee_rate) = max_fee_rate {
+            parameters.push(jsonrpc::arg(max_fee_rate));
+        }
+
+        let client = self.client()?;
+        let request = client.build_request("testmempoolaccept", &parameters);
+        let response = send(&client, request)?;
+        let results: Vec<MempoolAcceptResult> = response.result()?;
+
+        match results.into_iter().next() {
+            Some(result) if result.allowed => Ok(()),
+            Some(result) => Err(Error::TransactionRejected(
+                result.reject_reason.unwrap_or_default(),
+            )),
+            None => Err(Error::TransactionRejected("no result".to_string())),
+        }
+    }
+
+    /// Return the ids of all transactions in the mempool.
+    pub fn getrawmempool(&self) -> Result<Vec<elements::Txid>, Error> {
+        let client = self.client()?;
+        let request = client.build_request("getrawmempool", &[]);
+        let response = send(&client, request)?;
+
+        response.result().map_err(|e| e.into())
+    }
+
+    pub fn getrawtransaction(&self, txid: &elements::Txid) -> Result<elements::Transaction, Error> {
+        let parameters = [jsonrpc::arg(txid)];
+
+        let client = self.client()?;
+        let request = client.build_request("getrawtransaction", &parameters);
+        let response = send(&client, request)?;
+
+        let hex: String = response.result()?;
+        decode_tx(&hex)
     }
 
+    /// Return the transactions with the given ids, asking the node in a single batch.
+    ///
+    /// Transactions that the node doesn't know, for instance because they left the mempool
+    /// after their ids were listed, are `None`.
+    pub fn getrawtransactions(
+        &self,
+        txids: &[elements::Txid],
+    ) -> Result<Vec<Option<elements::Transaction>>, Error> {
+        if txids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let parameters: Vec<_> = txids.iter().map(|txid| [jsonrpc::arg(txid)]).collect();
+
+        let client = self.client()?;
+        let requests: Vec<_> = parameters
+            .iter()
+            .map(|parameters| client.build_request("getrawtransaction", parameters))
+            .collect();
+        let responses = send_batch(&client, &requests)?;
+
+        responses
+            .into_iter()
+            .map(|response| {
+                let response = match response {
+                    Some(response) => response,
+                    None => return Ok(None),
+                };
+                match response.result::<String>() {
+                    Ok(hex) => decode_tx(&hex).map(Some),
+                    Err(error) => match Error::from(error) {
+                        error if is_not_found(&error) => Ok(None),
+                        error => Err(error),
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// Broadcast the transaction.
+    ///
+    /// Sending a transaction that the node already has succeeds, so sends can be retried.
     pub fn sendrawtransaction(&self, tx: &elements::Transaction) -> Result<elements::Txid, Error> {
-        let hex =
-            serde_json::Value::String(elements::pset::serialize::Serialize::serialize(tx).to_hex());
+        let hex = serde_json::Value::String(tx_hex(tx));
         let parameters = [jsonrpc::arg(hex)];
 
         let client = self.client()?;
         let request = client.build_request("sendrawtransaction", &parameters);
-        let response = client.send_request(request)?;
+        let result = send(&client, request).and_then(|response| Ok(response.result()?));
 
-        response.result().map_err(|e| e.into())
+        match result {
+            Err(error) if is_already_known(&error) => Ok(tx.txid()),
+            result => result,
+        }
     }
 }
@@ -1,71 +1,1599 @@
 use std::borrow::Borrow;
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use bitcoin::key::PublicKey;
 use elements::bitcoin;
+use elements::hashes::Hash;
+use elements::hex::{FromHex, ToHex};
 use elements::secp256k1_zkp;
 use elements_miniscript as miniscript;
 use miniscript::{elements, Descriptor, MiniscriptKey, Preimage32, Satisfier, ToPublicKey};
+use serde::{Deserialize, Serialize};
 
 use crate::descriptor;
 use crate::error::Error;
 use crate::network::Network;
-use crate::state::{State, UtxoSet};
+use crate::output::{self, DisplayAmount};
+use crate::parse;
+use crate::state::{IssuedAsset, State, Utxo, UtxoSet};
 
-pub fn get_spendable_balance(state: &State) -> Result<bitcoin::Amount, Error> {
-    let mut descriptors: Vec<_> = state.child_descriptors().collect();
+/// Maximum size of OP_RETURN data that the node relays by default.
+pub const MAX_DATA_SIZE: usize = 80;
+
+/// Smallest output amount that nodes relay by default, for any output type.
+pub const DUST_LIMIT: bitcoin::Amount = bitcoin::Amount::from_sat(546);
+
+/// Multiple of the minimum fee rate of the mempool that [`speed_up`] targets by default.
+pub const SPEED_UP_FACTOR: f64 = 2.0;
+
+/// Maximum number of UTXOs to consolidate, unless configured otherwise.
+pub const DEFAULT_MAX_INPUTS: usize = 20;
+
+pub fn get_spendable_balance(
+    state: &mut State,
+    from_height: u64,
+) -> Result<bitcoin::Amount, Error> {
+    let mut descriptors = state.child_descriptors();
     descriptors.extend(state.assembly().spendable_descriptors().cloned());
-    let utxos = state.rpc().scan(&descriptors)?;
-    dbg!(&utxos);
+    let utxos = state.scan(&descriptors)?.confirmed_since(from_height);
     Ok(utxos.total_amount())
 }
 
-pub fn get_locked_balance(state: &State) -> Result<bitcoin::Amount, Error> {
+/// Return the amount of bitcoin that the wallet receives in unconfirmed transactions.
+///
+/// The UTXO set scan only sees confirmed coins,
+/// so each transaction in the mempool is checked for outputs to the wallet.
+/// This includes change of unconfirmed transactions that were sent by the wallet.
+///
+/// Only incoming coins are counted. Confirmed coins that an unconfirmed transaction spends
+/// are not subtracted: they stay in the spendable balance until the transaction is mined.
+pub fn get_pending_balance(state: &State) -> Result<bitcoin::Amount, Error> {
+    let scripts: HashSet<_> = state
+        .wallet_descriptors()
+        .iter()
+        .map(Descriptor::script_pubkey)
+        .collect();
+    let bitcoin_id = state.network().bitcoin_id();
+    let mut pending = bitcoin::Amount::ZERO;
+
+    let txids = state.rpc().getrawmempool()?;
+    // Transactions that left the mempool in the meantime are skipped
+    for tx in state
+        .rpc()
+        .getrawtransactions(&txids)?
+        .into_iter()
+        .flatten()
+    {
+        for txout in tx.output {
+            if !scripts.contains(&txout.script_pubkey) || txout.asset.explicit() != Some(bitcoin_id)
+            {
+                continue;
+            }
+            if let Some(value) = txout.value.explicit() {
+                pending += bitcoin::Amount::from_sat(value);
+            }
+        }
+    }
+
+    Ok(pending)
+}
+
+pub fn get_locked_balance(state: &mut State, from_height: u64) -> Result<bitcoin::Amount, Error> {
     let descriptors: Vec<_> = state.assembly().locked_descriptors().cloned().collect();
-    let utxos = state.rpc().scan(&descriptors)?;
-    dbg!(&utxos);
+    let utxos = state.scan(&descriptors)?.confirmed_since(from_height);
     Ok(utxos.total_amount())
 }
 
-pub fn send_to_address(state: &mut State, send_to: Payment) -> Result<elements::Txid, Error> {
-    let change_descriptor = state.next_child_descriptor()?;
+/// Amount to send, possibly relative to the spendable balance.
+#[derive(Clone, Copy, Debug)]
+pub enum SendAmount {
+    Exact(bitcoin::Amount),
+    /// Entire balance minus the fee
+    Max,
+    /// Entire balance minus the fee minus the given amount, which stays in the wallet
+    MaxMinus(bitcoin::Amount),
+    /// Percentage of the balance, between 1 and 100, but at most the balance minus the fee
+    Percent(u64),
+}
+
+impl SendAmount {
+    /// Return the amount to send from the given balance when paying the given fee.
+    pub fn resolve(
+        self,
+        balance: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<bitcoin::Amount, Error> {
+        let max = balance.checked_sub(fee);
+        let amount = match self {
+            SendAmount::Exact(amount) => Some(amount),
+            SendAmount::Max => max,
+            SendAmount::MaxMinus(keep) => max.and_then(|max| max.checked_sub(keep)),
+            SendAmount::Percent(percent) => {
+                let sat = u128::from(balance.to_sat()) * u128::from(percent) / 100;
+                // Like max, leave room for the fee, so "100%" sends everything
+                max.map(|max| bitcoin::Amount::from_sat(sat as u64).min(max))
+            }
+        };
+        amount.ok_or(Error::NotEnoughFunds)
+    }
+}
+
+impl FromStr for SendAmount {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "max" {
+            Ok(SendAmount::Max)
+        } else if let Some(keep) = s.strip_prefix("max-") {
+            Ok(SendAmount::MaxMinus(parse::amount(keep)?))
+        } else if let Some(percent) = s.strip_suffix('%') {
+            let percent: u64 = percent
+                .parse()
+                .map_err(|e: std::num::ParseIntError| Error::CouldNotParse(e.to_string()))?;
+            if !(1..=100).contains(&percent) {
+                return Err(Error::CouldNotParse(
+                    "percentage must be between 1 and 100".to_string(),
+                ));
+            }
+            Ok(SendAmount::Percent(percent))
+        } else {
+            Ok(SendAmount::Exact(parse::amount(s)?))
+        }
+    }
+}
+
+/// Kind of coins that a transaction may spend.
+///
+/// Spending key coins together with assembly coins links them on-chain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoinSource {
+    /// Key coins and coins of spendable assembly fragments
+    #[default]
+    Any,
+    /// Only coins of the wallet's key
+    Keys,
+    /// Only coins of spendable assembly fragments
+    Assembly,
+}
+
+/// Return the descriptors whose coins may be spent from the given source.
+fn spendable_descriptors(state: &State, source: CoinSource) -> Vec<Descriptor<PublicKey>> {
+    let mut descriptors = Vec::new();
+    if source != CoinSource::Assembly {
+        descriptors.extend(state.child_descriptors());
+    }
+    if source != CoinSource::Keys {
+        descriptors.extend(state.assembly().spendable_descriptors().cloned());
+    }
+    descriptors
+}
+
+/// Return the UTXOs that may be spent from the coin source of the options.
+///
+/// The UTXO set scan only sees confirmed transactions,
+/// so the coins of a previous send stay selectable until it confirms.
+/// Spending them again would conflict with that send.
+/// With [`SendOptions::skip_mempool_spends`], these coins are skipped.
+fn spendable_utxos(state: &mut State, options: &SendOptions) -> Result<UtxoSet, Error> {
+    let descriptors = spendable_descriptors(state, options.coins);
+    let utxo_set = state.scan(&descriptors)?;
+    if !options.skip_mempool_spends {
+        return Ok(utxo_set);
+    }
+
+    let mut utxos = Vec::with_capacity(utxo_set.0.len());
+    for utxo in utxo_set.0 {
+        // The node considers the mempool, so a coin that is spent there is gone
+        if state.rpc().gettxout(&utxo.outpoint)?.is_some() {
+            utxos.push(utxo);
+        } else {
+            eprintln!(
+                "Warning: skipping UTXO {} because a transaction in the mempool already spends it",
+                utxo.outpoint
+            );
+        }
+    }
+    Ok(UtxoSet(utxos))
+}
+
+/// Warn if the selected coins mix key coins with assembly coins.
+fn warn_if_mixed(selection: &UtxoSet) {
+    let assembly = selection
+        .0
+        .iter()
+        .filter(|u| !descriptor::get_cmrs(&u.descriptor).is_empty())
+        .count();
+    if 0 < assembly && assembly < selection.0.len() {
+        eprintln!(
+            "Warning: spending key coins together with assembly coins links them on-chain (use --coins to spend only one kind)"
+        );
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    /// Send even if the fee looks unreasonably high
+    pub force: bool,
+    /// Don't ask the node if it would accept the transaction before broadcasting it
+    pub no_check: bool,
+    /// Print the signed transaction in hex
+    pub print_hex: bool,
+    /// Write the signed transaction in hex to this file
+    pub save_hex: Option<PathBuf>,
+    /// Send change to this address instead of a fresh address of the wallet
+    pub change_address: Option<elements::Address>,
+    /// Include an OP_RETURN output with this data
+    pub data: Option<Vec<u8>>,
+    /// Kind of coins to spend
+    pub coins: CoinSource,
+    /// Lock time of the transaction, for fragments that check it
+    pub lock_time: Option<elements::LockTime>,
+    /// Sequence of the inputs, for fragments that check it
+    pub sequence: Option<elements::Sequence>,
+    /// Ask the node for each coin and skip those that the mempool already spends
+    pub skip_mempool_spends: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FragmentBalance {
+    pub cmr: simplicity::Cmr,
+    pub spendable: bool,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+}
+
+/// Return the balance of each assembly fragment, spendable fragments first.
+pub fn get_fragment_balances(
+    state: &mut State,
+    from_height: u64,
+) -> Result<Vec<FragmentBalance>, Error> {
+    let (spendable, locked) = state.assembly().partition_cmrs();
+    let descriptors: Vec<_> = state.assembly().descriptors().cloned().collect();
+    let utxos = state.scan(&descriptors)?.confirmed_since(from_height);
+
+    let balance = |cmr: &simplicity::Cmr| -> bitcoin::Amount {
+        utxos
+            .0
+            .iter()
+            .filter(|u| descriptor::get_cmrs(&u.descriptor).contains(cmr))
+            .map(|u| u.amount)
+            .sum()
+    };
+    let spendable = spendable.into_iter().map(|cmr| FragmentBalance {
+        cmr,
+        spendable: true,
+        amount: balance(&cmr),
+    });
+    let locked = locked.into_iter().map(|cmr| FragmentBalance {
+        cmr,
+        spendable: false,
+        amount: balance(&cmr),
+    });
+
+    Ok(spendable.chain(locked).collect())
+}
+
+/// Select the coins and compute the change of a send to the given address or script,
+/// without signing anything.
+pub fn plan_send(
+    state: &mut State,
+    recipient: Recipient,
+    amount: SendAmount,
+    options: &SendOptions,
+) -> Result<Plan, Error> {
+    let change_address = change_address(state, options)?;
+    let utxo_set = spendable_utxos(state, options)?;
+    let send_to = Payment {
+        amount: amount.resolve(utxo_set.total_amount(), state.fee())?,
+        recipient: recipient.into(),
+    };
+
+    check_amount(send_to.amount)?;
+    check_fee(
+        state.fee(),
+        send_to.amount,
+        state.max_fee_percent(),
+        options,
+    )?;
 
-    let mut descriptors: Vec<_> = state.child_descriptors().collect();
-    descriptors.extend(state.assembly().spendable_descriptors().cloned());
-    let utxo_set = state.rpc().scan(&descriptors)?;
     let (selection, available) = utxo_set
         .select_coins(send_to.amount + state.fee())
         .ok_or(Error::NotEnoughFunds)?;
+    warn_if_mixed(&selection);
+
+    // Sending the maximum amount leaves no change
+    let (change, fee) = change_payment(available, send_to.amount, state.fee(), change_address)?;
+
+    Ok(Plan {
+        utxos: selection,
+        send_to,
+        change,
+        fee,
+        input_amount: available,
+    })
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AddressBalance {
+    pub address: elements::Address,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+    pub utxos: usize,
+    /// Account and derivation index of a key address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<u32>,
+    /// CMRs of the Simplicity leaves of an assembly fragment address
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cmrs: Vec<simplicity::Cmr>,
+}
+
+impl fmt::Display for AddressBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({} UTXOs",
+            self.address,
+            DisplayAmount(self.amount),
+            self.utxos
+        )?;
+        if let (Some(account), Some(index)) = (&self.account, self.index) {
+            write!(f, ", account {} index {}", account, index)?;
+        }
+        if !self.cmrs.is_empty() {
+            write!(f, ", fragment {}", output::join_cmrs(&self.cmrs))?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Return the balance of each address of the wallet that holds coins, largest first.
+pub fn get_address_balances(
+    state: &mut State,
+    from_height: u64,
+) -> Result<Vec<AddressBalance>, Error> {
+    let params = state.network().address_params();
+    let mut balances: Vec<_> = state
+        .child_descriptor_origins()
+        .into_iter()
+        .map(|(account, index, descriptor)| {
+            (
+                descriptor,
+                Some(account.to_string()),
+                Some(index),
+                Vec::new(),
+            )
+        })
+        .collect();
+    balances.extend(state.assembly().descriptors().map(|descriptor| {
+        (
+            descriptor.clone(),
+            None,
+            None,
+            descriptor::get_cmrs(descriptor),
+        )
+    }));
+
+    let descriptors: Vec<_> = balances.iter().map(|(d, ..)| d.clone()).collect();
+    let utxos = state.scan(&descriptors)?.confirmed_since(from_height);
 
+    let mut balances: Vec<_> = balances
+        .into_iter()
+        .filter_map(|(descriptor, account, index, cmrs)| {
+            let script_pubkey = descriptor.script_pubkey();
+            let (amount, count) = utxos
+                .0
+                .iter()
+                .filter(|u| u.descriptor.script_pubkey() == script_pubkey)
+                .fold((bitcoin::Amount::ZERO, 0), |(amount, count), u| {
+                    (amount + u.amount, count + 1)
+                });
+            (0 < count).then(|| AddressBalance {
+                address: descriptor.address(params).expect("taproot address"),
+                amount,
+                utxos: count,
+                account,
+                index,
+                cmrs,
+            })
+        })
+        .collect();
+    balances.sort_by(|a, b| b.amount.cmp(&a.amount));
+    Ok(balances)
+}
+
+/// Create and sign a transaction that sends to the given address or script.
+///
+/// The transaction is not broadcast yet, see [`broadcast`].
+pub fn send_to_address(
+    state: &mut State,
+    recipient: Recipient,
+    amount: SendAmount,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    with_minimum_fee(state, |state| {
+        build_send_to_address(state, recipient.clone(), amount, options)
+    })
+}
+
+fn build_send_to_address(
+    state: &mut State,
+    recipient: Recipient,
+    amount: SendAmount,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    let plan = plan_send(state, recipient, amount, options)?;
+    sign_plan(state, plan, vec![], options.data.as_deref(), None, options)
+}
+
+/// Return the change of spending `available` on `amount` plus `fee`, if any is left,
+/// and the fee to pay.
+///
+/// Change below the dust limit would make the transaction non-standard,
+/// so it is added to the fee instead.
+fn change_payment(
+    available: bitcoin::Amount,
+    amount: bitcoin::Amount,
+    fee: bitcoin::Amount,
+    change_address: elements::Address,
+) -> Result<(Option<Payment>, bitcoin::Amount), Error> {
+    let change_amount = available
+        .checked_sub(amount)
+        .and_then(|rest| rest.checked_sub(fee))
+        .ok_or(Error::NotEnoughFunds)?;
+    if change_amount == bitcoin::Amount::ZERO {
+        return Ok((None, fee));
+    }
+    if change_amount < DUST_LIMIT {
+        eprintln!(
+            "Warning: adding the change of {} to the fee because it is below the dust limit of {}",
+            DisplayAmount(change_amount),
+            DisplayAmount(DUST_LIMIT)
+        );
+        return Ok((None, fee + change_amount));
+    }
     let change = Payment {
-        amount: available - send_to.amount - state.fee(), // available >= send_to.amount + fee
-        address: change_descriptor
+        amount: change_amount,
+        recipient: change_address.into(),
+    };
+    Ok((Some(change), fee))
+}
+
+/// Build and sign the transaction of the given plan,
+/// which additionally pays `more_recipients` and carries `data`.
+fn sign_plan(
+    state: &mut State,
+    plan: Plan,
+    more_recipients: Vec<Payment>,
+    data: Option<&[u8]>,
+    lock_time: Option<elements::LockTime>,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    let Plan {
+        utxos,
+        send_to,
+        change,
+        fee,
+        input_amount,
+    } = plan;
+    let input_count = utxos.0.len();
+
+    let mut builder = TransactionBuilder::new(state.network());
+
+    for input in utxos.into_inputs() {
+        builder.add_input(input);
+    }
+
+    let bitcoin_id = state.network().bitcoin_id();
+    builder.add_output(send_to.to_output(bitcoin_id));
+    for payment in &more_recipients {
+        builder.add_output(payment.to_output(bitcoin_id));
+    }
+    if let Some(change) = &change {
+        builder.add_output(change.to_output(bitcoin_id));
+    }
+    if let Some(data) = data {
+        builder.add_data_output(data)?;
+    }
+    builder.add_fee(fee);
+    if let Some(lock_time) = lock_time {
+        builder.set_lock_time(lock_time);
+    }
+
+    let tx = builder.sign(state)?;
+    let size = TransactionBuilder::size(&tx);
+    check_mempool(state, &tx, fee, &size, options)?;
+    Ok(Spend {
+        tx,
+        size,
+        uses_next_index: change.is_some() && options.change_address.is_none(),
+        send_to,
+        more_recipients,
+        change,
+        fee,
+        fee_rate: size.fee_rate(fee),
+        input_amount,
+        input_count,
+    })
+}
+
+/// Parameters of a send that are read from a JSON file.
+///
+/// Amounts are in satoshi.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PaymentRequest {
+    pub outputs: Vec<RequestedOutput>,
+    /// Fee to use instead of the configured fee
+    #[serde(default, with = "bitcoin::amount::serde::as_sat::opt")]
+    pub fee: Option<bitcoin::Amount>,
+    /// Spend exactly these UTXOs instead of selecting coins
+    #[serde(default, deserialize_with = "unique_outpoints")]
+    pub utxos: Option<Vec<elements::OutPoint>>,
+    /// Descriptors of UTXOs in `utxos` that the wallet doesn't find by itself
+    #[serde(default)]
+    pub descriptors: Vec<InputDescriptor>,
+    /// Block height or Unix time before which the transaction cannot be mined
+    #[serde(default)]
+    pub locktime: Option<u32>,
+    /// Data of an OP_RETURN output in hex
+    #[serde(default)]
+    pub data: Option<String>,
+}
+
+fn unique_outpoints<'de, D>(deserializer: D) -> Result<Option<Vec<elements::OutPoint>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let outpoints = Option::<Vec<elements::OutPoint>>::deserialize(deserializer)?;
+    let mut seen = HashSet::new();
+    for outpoint in outpoints.iter().flatten() {
+        if !seen.insert(outpoint) {
+            return Err(serde::de::Error::custom(format!(
+                "UTXO {} is listed twice",
+                outpoint
+            )));
+        }
+    }
+    Ok(outpoints)
+}
+
+/// Descriptor to spend the UTXO at the given outpoint with.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InputDescriptor {
+    pub outpoint: elements::OutPoint,
+    pub descriptor: Descriptor<PublicKey>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RequestedOutput {
+    pub address: elements::Address,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+}
+
+/// Create and sign a transaction that pays the outputs of the given request.
+///
+/// If the request sets a fee below the minimum of the node, fail with [`Error::FeeBelowMinimum`].
+/// The transaction is not broadcast yet, see [`broadcast`].
+pub fn send_payment_request(
+    state: &mut State,
+    request: PaymentRequest,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    match request.fee {
+        // A fee from the file is not raised: the node's minimum is reported as an error instead
+        Some(..) => build_payment_request(state, request, options),
+        None => with_minimum_fee(state, |state| {
+            build_payment_request(state, request.clone(), options)
+        }),
+    }
+}
+
+fn build_payment_request(
+    state: &mut State,
+    request: PaymentRequest,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    let mut recipients = request.outputs.into_iter().map(|output| Payment {
+        amount: output.amount,
+        recipient: output.address.into(),
+    });
+    let send_to = recipients.next().ok_or(Error::CouldNotParse(
+        "payment request has no outputs".to_string(),
+    ))?;
+    let more_recipients: Vec<_> = recipients.collect();
+    let data = match (request.data, &options.data) {
+        (Some(_), Some(_)) => {
+            return Err(Error::CouldNotParse(
+                "--data cannot be used with a payment request that has data".to_string(),
+            ))
+        }
+        (Some(hex), None) => {
+            Some(Vec::<u8>::from_hex(&hex).map_err(|e| Error::CouldNotParse(e.to_string()))?)
+        }
+        (None, data) => data.clone(),
+    };
+
+    let fee = request.fee.unwrap_or(state.fee());
+    let amount: bitcoin::Amount = std::iter::once(&send_to)
+        .chain(&more_recipients)
+        .map(|payment| payment.amount)
+        .sum();
+    for payment in std::iter::once(&send_to).chain(&more_recipients) {
+        check_amount(payment.amount)?;
+    }
+    check_fee(fee, amount, state.max_fee_percent(), options)?;
+
+    let change_address = change_address(state, options)?;
+    let utxo_set = spendable_utxos(state, options)?;
+    let outpoints = request.utxos.as_deref().unwrap_or_default();
+    if let Some(input) = request
+        .descriptors
+        .iter()
+        .find(|input| !outpoints.contains(&input.outpoint))
+    {
+        return Err(Error::CouldNotParse(format!(
+            "descriptor given for {}, which is not in utxos",
+            input.outpoint
+        )));
+    }
+    let (selection, available) = match request.utxos {
+        Some(outpoints) => {
+            let mut selected = Vec::with_capacity(outpoints.len());
+            for outpoint in outpoints {
+                let descriptor = request
+                    .descriptors
+                    .iter()
+                    .find(|input| input.outpoint == outpoint)
+                    .map(|input| &input.descriptor);
+                let utxo = match descriptor {
+                    Some(descriptor) => get_utxo_with_descriptor(state, outpoint, descriptor)?,
+                    None => utxo_set
+                        .0
+                        .iter()
+                        .find(|u| u.outpoint == outpoint)
+                        .ok_or(Error::UnknownUtxo(outpoint))?
+                        .clone(),
+                };
+                selected.push(utxo);
+            }
+            let selection = UtxoSet(selected);
+            let available = selection.total_amount();
+            if available < amount + fee {
+                return Err(Error::NotEnoughFunds);
+            }
+            (selection, available)
+        }
+        None => utxo_set
+            .select_coins(amount + fee)
+            .ok_or(Error::NotEnoughFunds)?,
+    };
+    warn_if_mixed(&selection);
+
+    let (change, fee) = change_payment(available, amount, fee, change_address)?;
+    let plan = Plan {
+        utxos: selection,
+        send_to,
+        change,
+        fee,
+        input_amount: available,
+    };
+    let lock_time = request.locktime.map(elements::LockTime::from_consensus);
+    sign_plan(
+        state,
+        plan,
+        more_recipients,
+        data.as_deref(),
+        lock_time,
+        options,
+    )
+}
+
+/// Return the address that receives the change of a send.
+fn change_address(state: &mut State, options: &SendOptions) -> Result<elements::Address, Error> {
+    match &options.change_address {
+        Some(change_address) => {
+            check_network(state, change_address)?;
+            Ok(change_address.clone())
+        }
+        None => {
+            // Never reuse an address that already received coins
+            for index in state.skip_used_indices()? {
+                eprintln!(
+                    "Warning: skipping index {} for change because its address already received coins",
+                    index
+                );
+            }
+            // Only advance the change index after a successful broadcast,
+            // so failed sends don't leave gaps in the derivation indices
+            Ok(state
+                .peek_child_descriptor()?
+                .address(state.network().address_params())
+                .expect("taproot address"))
+        }
+    }
+}
+
+/// Create and sign a child transaction that speeds up the given unconfirmed transaction.
+///
+/// The child spends the largest output of the parent that belongs to the wallet
+/// and pays enough fee to lift parent and child together to the given fee rate in sat/vB,
+/// which miners collect only by mining both transactions.
+/// The rate defaults to [`SPEED_UP_FACTOR`] times the minimum fee rate of the mempool.
+/// The transaction is not broadcast yet, see [`broadcast`].
+pub fn speed_up(
+    state: &mut State,
+    txid: elements::Txid,
+    fee_rate: Option<f64>,
+    options: &SendOptions,
+) -> Result<SpeedUp, Error> {
+    if !state.rpc().getrawmempool()?.contains(&txid) {
+        return Err(Error::NotInMempool(txid));
+    }
+    let parent = state.rpc().getrawtransaction(&txid)?;
+    let bitcoin_id = state.network().bitcoin_id();
+    let parent_fee = explicit_total(parent.output.iter().filter(|o| o.is_fee()), bitcoin_id);
+    let parent_vsize = TransactionBuilder::size(&parent).vsize;
+
+    let parent_fee_rate = parent_fee.to_sat() as f64 / parent_vsize as f64;
+    let fee_rate = match fee_rate {
+        Some(fee_rate) => fee_rate,
+        None => state.rpc().getmempoolinfo()?.min_fee_rate() * SPEED_UP_FACTOR,
+    };
+    if fee_rate <= parent_fee_rate {
+        return Err(Error::FeeRateTooLow {
+            fee_rate,
+            parent_fee_rate,
+        });
+    }
+
+    let descriptors = spendable_descriptors(state, options.coins);
+    let utxo = parent
+        .output
+        .iter()
+        .enumerate()
+        .filter(|(_, txout)| txout.asset.explicit() == Some(bitcoin_id))
+        .filter_map(|(vout, txout)| {
+            let amount = txout.value.explicit()?;
+            let descriptor = descriptors
+                .iter()
+                .find(|d| d.script_pubkey() == txout.script_pubkey)?;
+            Some(Utxo {
+                descriptor: descriptor.clone(),
+                amount: bitcoin::Amount::from_sat(amount),
+                asset: bitcoin_id,
+                outpoint: elements::OutPoint::new(txid, vout as u32),
+                height: 0,
+            })
+        })
+        .max_by_key(|utxo| utxo.amount)
+        .ok_or(Error::NoWalletOutput(txid))?;
+
+    for index in state.skip_used_indices()? {
+        eprintln!(
+            "Warning: skipping index {} because its address already received coins",
+            index
+        );
+    }
+    let address = state
+        .peek_child_descriptor()?
+        .address(state.network().address_params())
+        .expect("taproot address");
+
+    // The size of the child doesn't depend on its fee, so sign it once to learn the size
+    let (_, size, _) = sign_child(state, &utxo, bitcoin::Amount::ZERO, &address)?;
+    let package_vsize = parent_vsize + size.vsize;
+    let package_fee = bitcoin::Amount::from_sat((fee_rate * package_vsize as f64).ceil() as u64);
+    let fee = package_fee
+        .checked_sub(parent_fee)
+        .expect("fee rate is above the rate of the parent");
+
+    let send_to_amount = utxo.amount.checked_sub(fee).ok_or(Error::NotEnoughFunds)?;
+    check_amount(send_to_amount)?;
+    check_fee(fee, send_to_amount, state.max_fee_percent(), options)?;
+
+    let input_amount = utxo.amount;
+    let (tx, size, send_to) = sign_child(state, &utxo, fee, &address)?;
+    check_mempool(state, &tx, fee, &size, options)?;
+    let package_fee = parent_fee + fee;
+    let package_fee_rate = package_fee.to_sat() as f64 / (parent_vsize + size.vsize) as f64;
+    Ok(SpeedUp {
+        spend: Spend {
+            tx,
+            size,
+            uses_next_index: true,
+            send_to,
+            more_recipients: vec![],
+            change: None,
+            fee,
+            fee_rate: size.fee_rate(fee),
+            input_amount,
+            input_count: 1,
+        },
+        parent: txid,
+        parent_fee,
+        package_fee_rate,
+    })
+}
+
+/// Sign a child transaction that sends the UTXO to the address, minus the given fee.
+fn sign_child(
+    state: &State,
+    utxo: &Utxo,
+    fee: bitcoin::Amount,
+    address: &elements::Address,
+) -> Result<(elements::Transaction, TransactionSize, Payment), Error> {
+    let send_to = Payment {
+        amount: utxo.amount.checked_sub(fee).ok_or(Error::NotEnoughFunds)?,
+        recipient: address.clone().into(),
+    };
+
+    let mut builder = TransactionBuilder::new(state.network());
+
+    for input in UtxoSet(vec![utxo.clone()]).into_inputs() {
+        builder.add_input(input);
+    }
+
+    builder.add_output(send_to.to_output(state.network().bitcoin_id()));
+    builder.add_fee(fee);
+
+    let tx = builder.sign(state)?;
+    let size = TransactionBuilder::size(&tx);
+    Ok((tx, size, send_to))
+}
+
+/// Create and sign a transaction that merges the smallest UTXOs of the wallet
+/// into a single output at a fresh internal address.
+///
+/// At most `max_inputs` UTXOs are spent. The fee is paid from the consolidated amount.
+/// The transaction is not broadcast yet, see [`broadcast`].
+pub fn consolidate(
+    state: &mut State,
+    max_inputs: usize,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    with_minimum_fee(state, |state| build_consolidate(state, max_inputs, options))
+}
+
+fn build_consolidate(
+    state: &mut State,
+    max_inputs: usize,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    for index in state.skip_used_indices()? {
+        eprintln!(
+            "Warning: skipping index {} for change because its address already received coins",
+            index
+        );
+    }
+    // Like change, the index is only advanced after a successful broadcast
+    let descriptor = state.peek_child_descriptor()?;
+
+    let selection = spendable_utxos(state, options)?.smallest(max_inputs);
+    warn_if_mixed(&selection);
+    let input_amount = selection.total_amount();
+    let input_count = selection.0.len();
+
+    let send_to = Payment {
+        amount: input_amount
+            .checked_sub(state.fee())
+            .ok_or(Error::NotEnoughFunds)?,
+        recipient: descriptor
             .address(state.network().address_params())
-            .expect("taproot address"),
+            .expect("taproot address")
+            .into(),
+    };
+    check_amount(send_to.amount)?;
+    check_fee(
+        state.fee(),
+        send_to.amount,
+        state.max_fee_percent(),
+        options,
+    )?;
+
+    let mut builder = TransactionBuilder::new(state.network());
+
+    for input in selection.into_inputs() {
+        builder.add_input(input);
+    }
+
+    builder.add_output(send_to.to_output(state.network().bitcoin_id()));
+    builder.add_fee(state.fee());
+
+    let tx = builder.sign(state)?;
+    let size = TransactionBuilder::size(&tx);
+    check_mempool(state, &tx, state.fee(), &size, options)?;
+    Ok(Spend {
+        tx,
+        size,
+        uses_next_index: true,
+        send_to,
+        more_recipients: vec![],
+        change: None,
+        fee: state.fee(),
+        fee_rate: size.fee_rate(state.fee()),
+        input_amount,
+        input_count,
+    })
+}
+
+/// Create and sign a transaction that issues a new asset.
+///
+/// The asset, the optional reissuance token and the bitcoin change
+/// all go to the address at the next unused index of the wallet.
+/// The transaction is not broadcast yet, see [`broadcast_issuance`].
+pub fn issue_asset(
+    state: &mut State,
+    amount: u64,
+    token_amount: u64,
+    options: &SendOptions,
+) -> Result<Issuance, Error> {
+    with_minimum_fee(state, |state| {
+        build_issue_asset(state, amount, token_amount, options)
+    })
+}
+
+fn build_issue_asset(
+    state: &mut State,
+    amount: u64,
+    token_amount: u64,
+    options: &SendOptions,
+) -> Result<Issuance, Error> {
+    for index in state.skip_used_indices()? {
+        eprintln!(
+            "Warning: skipping index {} because its address already received coins",
+            index
+        );
+    }
+    let address = state
+        .peek_child_descriptor()?
+        .address(state.network().address_params())
+        .expect("taproot address");
+
+    let (selection, available) = spendable_utxos(state, options)?
+        .select_coins(state.fee())
+        .ok_or(Error::NotEnoughFunds)?;
+    warn_if_mixed(&selection);
+    let input_count = selection.0.len();
+    let mut inputs = selection.into_inputs();
+
+    // The asset id is derived from the outpoint of the input that carries the issuance
+    let contract_hash = elements::ContractHash::all_zeros();
+    let entropy =
+        elements::AssetId::generate_asset_entropy(inputs[0].input.previous_output, contract_hash);
+    let asset = elements::AssetId::from_entropy(entropy);
+    let token = (0 < token_amount)
+        .then(|| elements::AssetId::reissuance_token_from_entropy(entropy, false));
+    inputs[0].input.asset_issuance = elements::AssetIssuance {
+        asset_blinding_nonce: secp256k1_zkp::ZERO_TWEAK,
+        asset_entropy: contract_hash.to_byte_array(),
+        amount: elements::confidential::Value::Explicit(amount),
+        inflation_keys: match token {
+            Some(..) => elements::confidential::Value::Explicit(token_amount),
+            None => elements::confidential::Value::Null,
+        },
+    };
+
+    let mut builder = TransactionBuilder::new(state.network());
+
+    for input in inputs {
+        builder.add_input(input);
+    }
+
+    let asset_output = |asset: elements::AssetId, amount: u64| elements::TxOut {
+        asset: elements::confidential::Asset::Explicit(asset),
+        value: elements::confidential::Value::Explicit(amount),
+        nonce: elements::confidential::Nonce::Null,
+        script_pubkey: address.script_pubkey(),
+        witness: elements::TxOutWitness::default(),
     };
+    builder.add_output(asset_output(asset, amount));
+    if let Some(token) = token {
+        builder.add_output(asset_output(token, token_amount));
+    }
+    let (change, fee) = change_payment(
+        available,
+        bitcoin::Amount::ZERO,
+        state.fee(),
+        address.clone(),
+    )?;
+    if let Some(change) = change {
+        builder.add_output(change.to_output(state.network().bitcoin_id()));
+    }
+    builder.add_fee(fee);
+
+    let tx = builder.sign(state)?;
+    let size = TransactionBuilder::size(&tx);
+    check_mempool(state, &tx, fee, &size, options)?;
+    Ok(Issuance {
+        tx,
+        size,
+        asset,
+        amount,
+        token,
+        token_amount,
+        address,
+        fee,
+        fee_rate: size.fee_rate(fee),
+        input_amount: available,
+        input_count,
+    })
+}
+
+/// Return the UTXOs of the given assembly fragment.
+///
+/// Fail if there is no satisfaction to spend the fragment.
+pub fn get_assembly_utxos(state: &mut State, cmr: &simplicity::Cmr) -> Result<UtxoSet, Error> {
+    let descriptor = state
+        .assembly()
+        .get(cmr)
+        .ok_or(Error::UnknownAssembly(*cmr))?
+        .clone();
+    if state.assembly().get_satisfaction(cmr).is_none() {
+        return Err(Error::MissingSatisfaction(*cmr));
+    }
+    state.scan(&[descriptor])
+}
+
+/// Outcome of a trial spend of an assembly fragment, see [`check_assembly_spend`].
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case", tag = "status", content = "reason")]
+pub enum Verification {
+    /// The node ran the program and accepted the spend
+    Passed,
+    /// The node didn't run the program, so the satisfaction was not checked
+    Skipped(String),
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verification::Passed => write!(f, "verified by a trial spend"),
+            Verification::Skipped(reason) => write!(f, "not verified: {}", reason),
+        }
+    }
+}
+
+/// Ask the node to run the program of the given assembly fragment with the stored satisfaction.
+///
+/// The trial transaction pays an entire UTXO of the fragment as fee,
+/// so neither the fee setting nor the dust limit play a role, and the node's cap on the fee rate
+/// is lifted for the trial. Nothing is broadcast.
+/// Set the lock time and sequence for fragments that check them.
+///
+/// Fail if the satisfaction cannot be signed or if the node rejects the scripts of the trial.
+/// If the fragment has no UTXO, or if the node rejects the trial for another reason,
+/// such as a lock time in the future, the satisfaction is skipped.
+pub fn check_assembly_spend(
+    state: &mut State,
+    cmr: &simplicity::Cmr,
+    options: &SendOptions,
+) -> Result<Verification, Error> {
+    let utxo = match get_assembly_utxos(state, cmr)?.0.into_iter().next() {
+        Some(utxo) => utxo,
+        None => {
+            return Ok(Verification::Skipped(
+                "the fragment has no UTXO to spend".to_string(),
+            ))
+        }
+    };
+    let fee = utxo.amount;
 
     let mut builder = TransactionBuilder::new(state.network());
+    for mut input in UtxoSet(vec![utxo]).into_inputs() {
+        if let Some(sequence) = options.sequence {
+            input.input.sequence = sequence;
+        }
+        builder.add_input(input);
+    }
+    if let Some(lock_time) = options.lock_time {
+        builder.set_lock_time(lock_time);
+    }
+    builder.add_fee(fee);
+
+    let tx = builder.sign(state)?;
+    // The fee of the trial is as large as the UTXO, so lift the node's cap on the fee rate
+    match state.rpc().testmempoolaccept(&tx, Some(0.0)) {
+        Ok(()) => Ok(Verification::Passed),
+        Err(Error::TransactionRejected(reason)) if !is_script_failure(&reason) => Ok(
+            Verification::Skipped(format!("the node rejected the trial: {}", reason)),
+        ),
+        Err(error) => Err(error),
+    }
+}
 
-    for input in selection.into_inputs(state.network().bitcoin_id()) {
+/// Return whether the node rejected a transaction because one of its scripts failed.
+fn is_script_failure(reject_reason: &str) -> bool {
+    reject_reason.contains("script-verify-flag")
+}
+
+/// Create and sign a transaction that sends the entire UTXO to the given address.
+///
+/// The fee is paid from the UTXO. The transaction is not broadcast yet, see [`broadcast`].
+pub fn sweep_utxo(
+    state: &mut State,
+    utxo: Utxo,
+    address: elements::Address,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    with_minimum_fee(state, |state| {
+        build_sweep_utxo(state, utxo.clone(), address.clone(), options)
+    })
+}
+
+fn build_sweep_utxo(
+    state: &State,
+    utxo: Utxo,
+    address: elements::Address,
+    options: &SendOptions,
+) -> Result<Spend, Error> {
+    let input_amount = utxo.amount;
+    let send_to = Payment {
+        amount: input_amount
+            .checked_sub(state.fee())
+            .ok_or(Error::NotEnoughFunds)?,
+        recipient: address.into(),
+    };
+    check_amount(send_to.amount)?;
+    check_fee(
+        state.fee(),
+        send_to.amount,
+        state.max_fee_percent(),
+        options,
+    )?;
+
+    let mut builder = TransactionBuilder::new(state.network());
+
+    for mut input in UtxoSet(vec![utxo]).into_inputs() {
+        if let Some(sequence) = options.sequence {
+            input.input.sequence = sequence;
+        }
         builder.add_input(input);
     }
+    if let Some(lock_time) = options.lock_time {
+        builder.set_lock_time(lock_time);
+    }
 
     builder.add_output(send_to.to_output(state.network().bitcoin_id()));
-    builder.add_output(change.to_output(state.network().bitcoin_id()));
     builder.add_fee(state.fee());
 
-    let tx = builder.sign(state).ok_or(Error::CouldNotSatisfy)?;
-    let txid = state.rpc().sendrawtransaction(&tx)?;
+    let tx = builder.sign(state)?;
+    let size = TransactionBuilder::size(&tx);
+    check_mempool(state, &tx, state.fee(), &size, options)?;
+    Ok(Spend {
+        tx,
+        size,
+        uses_next_index: false,
+        send_to,
+        more_recipients: vec![],
+        change: None,
+        fee: state.fee(),
+        fee_rate: size.fee_rate(state.fee()),
+        input_amount,
+        input_count: 1,
+    })
+}
+
+fn is_fee_too_high(fee: bitcoin::Amount, amount: bitcoin::Amount, max_percent: u64) -> bool {
+    u128::from(fee.to_sat()) * 100 > u128::from(amount.to_sat()) * u128::from(max_percent)
+}
+
+/// Ask the node for the UTXO at the given outpoint and spend it with the given descriptor,
+/// instead of a descriptor from the UTXO set scan.
+fn get_utxo_with_descriptor(
+    state: &State,
+    outpoint: elements::OutPoint,
+    descriptor: &Descriptor<PublicKey>,
+) -> Result<Utxo, Error> {
+    let txout = state
+        .rpc()
+        .gettxout(&outpoint)?
+        .ok_or(Error::UnknownUtxo(outpoint))?;
+    if txout.script_pub_key.hex != descriptor.script_pubkey() {
+        return Err(Error::CouldNotParse(format!(
+            "descriptor does not match the output script of {}",
+            outpoint
+        )));
+    }
+    let (amount, asset) = match (txout.value, txout.asset) {
+        (Some(amount), Some(asset)) if asset == state.network().bitcoin_id() => (amount, asset),
+        _ => return Err(Error::UnknownUtxo(outpoint)),
+    };
+    let height = match txout.confirmations {
+        0 => 0,
+        confirmations => state.rpc().getblockcount()? + 1 - confirmations,
+    };
+
+    Ok(Utxo {
+        descriptor: descriptor.clone(),
+        amount,
+        asset,
+        outpoint,
+        height,
+    })
+}
+
+/// Build a transaction with `build`, and build it again with a higher fee
+/// if the fee is below the minimum that the node accepts.
+///
+/// The higher fee is used for this command only; the configured fee stays the same.
+fn with_minimum_fee<T>(
+    state: &mut State,
+    mut build: impl FnMut(&mut State) -> Result<T, Error>,
+) -> Result<T, Error> {
+    match build(state) {
+        Err(Error::FeeBelowMinimum { fee, minimum }) => {
+            eprintln!(
+                "Warning: raising the fee from {} to {}, the minimum that the node accepts",
+                fee, minimum
+            );
+            state.raise_fee(minimum);
+            build(state)
+        }
+        result => result,
+    }
+}
+
+/// Ask the node if it would accept the transaction into its mempool, unless disabled.
+///
+/// A fee below the minimum of the node is reported as [`Error::FeeBelowMinimum`],
+/// so the transaction can be built again with a higher fee.
+fn check_mempool(
+    state: &State,
+    tx: &elements::Transaction,
+    fee: bitcoin::Amount,
+    size: &TransactionSize,
+    options: &SendOptions,
+) -> Result<(), Error> {
+    if options.no_check {
+        return Ok(());
+    }
+    let minimum = state.rpc().getmempoolinfo()?.min_fee(size.vsize);
+    if fee < minimum {
+        return Err(Error::FeeBelowMinimum { fee, minimum });
+    }
+    state.rpc().testmempoolaccept(tx, None)
+}
+
+/// Return an error if the amount of an output is too small to be relayed.
+fn check_amount(amount: bitcoin::Amount) -> Result<(), Error> {
+    if amount < DUST_LIMIT {
+        Err(Error::AmountTooSmall(amount))
+    } else {
+        Ok(())
+    }
+}
+
+/// Return an error if the fee is zero or unreasonably high for the amount sent,
+/// unless the user forces the send.
+fn check_fee(
+    fee: bitcoin::Amount,
+    amount: bitcoin::Amount,
+    max_percent: u64,
+    options: &SendOptions,
+) -> Result<(), Error> {
+    if options.force {
+        Ok(())
+    } else if fee == bitcoin::Amount::ZERO {
+        Err(Error::ZeroFee)
+    } else if is_fee_too_high(fee, amount, max_percent) {
+        Err(Error::FeeTooHigh(fee))
+    } else {
+        Ok(())
+    }
+}
+
+/// Return an error if the address is not for the network of the wallet.
+fn check_network(state: &State, address: &elements::Address) -> Result<(), Error> {
+    if address.params == state.network().address_params() {
+        Ok(())
+    } else {
+        Err(Error::WrongNetwork(address.clone()))
+    }
+}
+
+/// Broadcast a signed transaction and mark its change address as used.
+pub fn broadcast(state: &mut State, spend: &Spend) -> Result<elements::Txid, Error> {
+    let txid = state.rpc().sendrawtransaction(&spend.tx)?;
+    if spend.uses_next_index {
+        state.advance_index()?;
+    }
+    state.clear_utxo_cache();
     Ok(txid)
 }
 
-#[derive(Clone, Debug)]
+/// Broadcast a signed issuance transaction and remember the issued asset.
+pub fn broadcast_issuance(state: &mut State, issuance: &Issuance) -> Result<elements::Txid, Error> {
+    let txid = state.rpc().sendrawtransaction(&issuance.tx)?;
+    state.advance_index()?;
+    state.add_issued_asset(IssuedAsset {
+        asset: issuance.asset,
+        token: issuance.token,
+        txid,
+    });
+    state.clear_utxo_cache();
+    Ok(txid)
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AssetBalance {
+    pub asset: elements::AssetId,
+    /// Whether this is the reissuance token of an issued asset
+    pub token: bool,
+    pub amount: u64,
+}
+
+/// Return the balance of each asset and token that was issued by the wallet.
+pub fn get_asset_balances(state: &mut State, from_height: u64) -> Result<Vec<AssetBalance>, Error> {
+    let descriptors = state.child_descriptors();
+    let mut assets = Vec::new();
+    for issued in state.issued_assets() {
+        assets.push((issued.asset, false));
+        if let Some(token) = issued.token {
+            assets.push((token, true));
+        }
+    }
+
+    let mut balances = Vec::with_capacity(assets.len());
+    for (asset, token) in assets {
+        let utxos = state
+            .scan_asset(&descriptors, asset)?
+            .confirmed_since(from_height);
+        balances.push(AssetBalance {
+            asset,
+            token,
+            amount: utxos.total_amount().to_sat(),
+        });
+    }
+    Ok(balances)
+}
+
+/// Signed child transaction that speeds up its parent, see [`speed_up`].
+#[derive(Serialize, Clone, Debug)]
+pub struct SpeedUp {
+    #[serde(flatten)]
+    pub spend: Spend,
+    pub parent: elements::Txid,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub parent_fee: bitcoin::Amount,
+    /// Fee rate of parent and child together in sat/vB
+    pub package_fee_rate: f64,
+}
+
+impl fmt::Display for SpeedUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.spend)?;
+        writeln!(
+            f,
+            "Parent:    {} paying {}",
+            self.parent,
+            DisplayAmount(self.parent_fee)
+        )?;
+        write!(f, "Package:   {:.1} sat/vB", self.package_fee_rate)
+    }
+}
+
+/// Signed issuance transaction that is ready to be broadcast.
+#[derive(Serialize, Clone, Debug)]
+pub struct Issuance {
+    #[serde(skip)]
+    pub tx: elements::Transaction,
+    pub asset: elements::AssetId,
+    pub amount: u64,
+    pub token: Option<elements::AssetId>,
+    pub token_amount: u64,
+    pub address: elements::Address,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
+    /// Fee rate that the signed transaction achieves in sat/vB
+    pub fee_rate: f64,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub input_amount: bitcoin::Amount,
+    pub input_count: usize,
+    pub size: TransactionSize,
+}
+
+impl fmt::Display for Issuance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Asset:     {}", self.asset)?;
+        writeln!(f, "Amount:    {}", self.amount)?;
+        if let Some(token) = &self.token {
+            writeln!(f, "Token:     {} ({})", token, self.token_amount)?;
+        }
+        writeln!(f, "Address:   {}", self.address)?;
+        writeln!(
+            f,
+            "Fee:       {} ({:.1} sat/vB)",
+            DisplayAmount(self.fee),
+            self.fee_rate
+        )?;
+        writeln!(
+            f,
+            "Inputs:    {} from {} UTXOs",
+            DisplayAmount(self.input_amount),
+            self.input_count
+        )?;
+        write!(f, "Size:      {}", self.size)
+    }
+}
+
+/// Signed transaction that is ready to be broadcast.
+#[derive(Serialize, Clone, Debug)]
+pub struct Spend {
+    #[serde(skip)]
+    pub tx: elements::Transaction,
+    /// Whether an output pays to the address at the next unused index of the wallet
+    #[serde(skip)]
+    pub uses_next_index: bool,
+    pub send_to: Payment,
+    /// Further recipients of a payment request
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub more_recipients: Vec<Payment>,
+    pub change: Option<Payment>,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
+    /// Fee rate that the signed transaction achieves in sat/vB
+    pub fee_rate: f64,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub input_amount: bitcoin::Amount,
+    pub input_count: usize,
+    pub size: TransactionSize,
+}
+
+impl fmt::Display for Spend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Recipient: {}", self.send_to.recipient)?;
+        writeln!(f, "Amount:    {}", DisplayAmount(self.send_to.amount))?;
+        for payment in &self.more_recipients {
+            writeln!(f, "Recipient: {}", payment.recipient)?;
+            writeln!(f, "Amount:    {}", DisplayAmount(payment.amount))?;
+        }
+        writeln!(
+            f,
+            "Fee:       {} ({:.1} sat/vB)",
+            DisplayAmount(self.fee),
+            self.fee_rate
+        )?;
+        if let Some(change) = &self.change {
+            writeln!(
+                f,
+                "Change:    {} to {}",
+                DisplayAmount(change.amount),
+                change.recipient
+            )?;
+        }
+        writeln!(
+            f,
+            "Inputs:    {} from {} UTXOs",
+            DisplayAmount(self.input_amount),
+            self.input_count
+        )?;
+        write!(f, "Size:      {}", self.size)
+    }
+}
+
+/// Coins and outputs of a send, before the transaction is built and signed.
+#[derive(Serialize, Clone, Debug)]
+pub struct Plan {
+    pub utxos: UtxoSet,
+    pub send_to: Payment,
+    pub change: Option<Payment>,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub fee: bitcoin::Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub input_amount: bitcoin::Amount,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Recipient: {}", self.send_to.recipient)?;
+        writeln!(f, "Amount:    {}", DisplayAmount(self.send_to.amount))?;
+        writeln!(f, "Fee:       {}", DisplayAmount(self.fee))?;
+        match &self.change {
+            Some(change) => writeln!(
+                f,
+                "Change:    {} to {}",
+                DisplayAmount(change.amount),
+                change.recipient
+            )?,
+            None => writeln!(f, "Change:    none")?,
+        }
+        write!(
+            f,
+            "Inputs:    {} from {} UTXOs",
+            DisplayAmount(self.input_amount),
+            self.utxos.0.len()
+        )?;
+        for utxo in &self.utxos.0 {
+            write!(f, "\n    {} {}", utxo.outpoint, DisplayAmount(utxo.amount))?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of a signed transaction.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct TransactionSize {
+    /// Serialized size in bytes
+    pub size: usize,
+    pub weight: usize,
+    /// Virtual size in vbytes, which is the weight divided by 4, rounded up
+    pub vsize: usize,
+}
+
+impl TransactionSize {
+    /// Return the fee rate in sat/vB that the given fee achieves for this size.
+    ///
+    /// The witness of a Simplicity spend is large,
+    /// so the rate can be much lower than the fee suggests.
+    pub fn fee_rate(&self, fee: bitcoin::Amount) -> f64 {
+        fee.to_sat() as f64 / self.vsize as f64
+    }
+}
+
+impl fmt::Display for TransactionSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes, {} weight units, {} vbytes",
+            self.size, self.weight, self.vsize
+        )
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
 pub struct Payment {
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
     pub amount: bitcoin::Amount,
-    pub address: elements::Address,
+    #[serde(flatten)]
+    pub recipient: Recipient,
+}
+
+/// Receiver of a payment.
+///
+/// Most payments go to an address, but some output scripts have no address encoding.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Recipient {
+    Address(elements::Address),
+    /// Script that is used as-is
+    Script(elements::Script),
+}
+
+impl Recipient {
+    pub fn script_pubkey(&self) -> elements::Script {
+        match self {
+            Recipient::Address(address) => address.script_pubkey(),
+            Recipient::Script(script) => script.clone(),
+        }
+    }
+}
+
+impl From<elements::Address> for Recipient {
+    fn from(address: elements::Address) -> Self {
+        Recipient::Address(address)
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recipient::Address(address) => write!(f, "{}", address),
+            Recipient::Script(script) => write!(f, "script {}", script.as_bytes().to_hex()),
+        }
+    }
 }
 
 impl Payment {
@@ -74,12 +1602,34 @@ impl Payment {
             asset: elements::confidential::Asset::Explicit(bitcoin_id),
             value: elements::confidential::Value::Explicit(self.amount.to_sat()),
             nonce: elements::confidential::Nonce::Null,
-            script_pubkey: self.address.script_pubkey(),
+            script_pubkey: self.recipient.script_pubkey(),
             witness: elements::TxOutWitness::default(),
         }
     }
 }
 
+/// Order in which UTXOs are listed.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum UtxoOrder {
+    /// Oldest first
+    #[default]
+    Height,
+    /// Largest first
+    Amount,
+}
+
+impl FromStr for UtxoOrder {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "height" => Ok(UtxoOrder::Height),
+            "amount" => Ok(UtxoOrder::Amount),
+            _ => Err(Error::CouldNotParse(format!("unknown sort order: {}", s))),
+        }
+    }
+}
+
 impl UtxoSet {
     pub fn select_coins(&self, amount: bitcoin::Amount) -> Option<(Self, bitcoin::Amount)> {
         let mut selected_amount = bitcoin::Amount::ZERO;
@@ -101,11 +1651,37 @@ impl UtxoSet {
         }
     }
 
+    /// Keep only the `count` UTXOs with the smallest amounts.
+    pub fn smallest(mut self, count: usize) -> Self {
+        self.0.sort_by_key(|u| u.amount);
+        self.0.truncate(count);
+        self
+    }
+
+    /// Sort the UTXOs in the given order and keep at most `limit` of them,
+    /// skipping the first `offset`.
+    pub fn page(mut self, order: UtxoOrder, offset: usize, limit: Option<usize>) -> Self {
+        match order {
+            UtxoOrder::Height => self.0.sort_by_key(|u| u.height),
+            UtxoOrder::Amount => self.0.sort_by_key(|u| std::cmp::Reverse(u.amount)),
+        }
+        let utxos = self.0.into_iter().skip(offset);
+        match limit {
+            Some(limit) => Self(utxos.take(limit).collect()),
+            None => Self(utxos.collect()),
+        }
+    }
+
+    /// Keep only the UTXOs that were confirmed at the given height or later.
+    pub fn confirmed_since(self, height: u64) -> Self {
+        Self(self.0.into_iter().filter(|u| height <= u.height).collect())
+    }
+
     pub fn total_amount(&self) -> bitcoin::Amount {
         self.0.iter().map(|u| u.amount).sum()
     }
 
-    pub fn into_inputs(self, bitcoin_id: elements::AssetId) -> Vec<Input> {
+    pub fn into_inputs(self) -> Vec<Input> {
         let mut inputs = Vec::with_capacity(self.0.len());
 
         for utxo in self.0 {
@@ -118,7 +1694,7 @@ impl UtxoSet {
                 witness: elements::TxInWitness::default(),
             };
             let prevout = elements::TxOut {
-                asset: elements::confidential::Asset::Explicit(bitcoin_id),
+                asset: elements::confidential::Asset::Explicit(utxo.asset),
                 value: elements::confidential::Value::Explicit(utxo.amount.to_sat()),
                 nonce: elements::confidential::Nonce::Null,
                 script_pubkey: utxo.descriptor.script_pubkey(),
@@ -142,12 +1718,25 @@ pub struct Input {
     pub prevout: elements::TxOut,
 }
 
+/// Return the sum of the explicit values of the given outputs of the given asset.
+fn explicit_total<'a, I: Iterator<Item = &'a elements::TxOut>>(
+    txouts: I,
+    asset: elements::AssetId,
+) -> bitcoin::Amount {
+    txouts
+        .filter(|txout| txout.asset.explicit() == Some(asset))
+        .filter_map(|txout| txout.value.explicit())
+        .map(bitcoin::Amount::from_sat)
+        .sum()
+}
+
 struct TransactionBuilder {
     inputs: Vec<elements::TxIn>,
     descriptors: Vec<Descriptor<PublicKey>>,
     prevouts: Vec<elements::TxOut>,
     outputs: Vec<elements::TxOut>,
     network: Network,
+    lock_time: elements::LockTime,
 }
 
 impl TransactionBuilder {
@@ -158,6 +1747,7 @@ impl TransactionBuilder {
             prevouts: vec![],
             outputs: vec![],
             network,
+            lock_time: elements::LockTime::ZERO,
         }
     }
 
@@ -171,57 +1761,224 @@ impl TransactionBuilder {
         self.outputs.push(output);
     }
 
+    pub fn set_lock_time(&mut self, lock_time: elements::LockTime) {
+        self.lock_time = lock_time;
+    }
+
+    /// Add an unspendable output without value that carries the given data.
+    pub fn add_data_output(&mut self, data: &[u8]) -> Result<(), Error> {
+        if MAX_DATA_SIZE < data.len() {
+            return Err(Error::DataTooLarge(data.len()));
+        }
+        let output = elements::TxOut {
+            asset: elements::confidential::Asset::Explicit(self.network.bitcoin_id()),
+            value: elements::confidential::Value::Explicit(0),
+            nonce: elements::confidential::Nonce::Null,
+            script_pubkey: elements::Script::new_op_return(data),
+            witness: elements::TxOutWitness::default(),
+        };
+        self.outputs.push(output);
+        Ok(())
+    }
+
     pub fn add_fee(&mut self, amount: bitcoin::Amount) {
         let output = elements::TxOut::new_fee(amount.to_sat(), self.network.bitcoin_id());
         self.outputs.push(output);
     }
 
+    /// Check that the inputs pay exactly for the outputs plus the fee.
+    ///
+    /// Elements transactions have an explicit fee output,
+    /// so a mistake in the amounts would otherwise only be caught by the node.
+    ///
+    /// Only bitcoin is checked, because issued assets have no inputs.
+    fn check_balance(&self) -> Result<(), Error> {
+        let bitcoin_id = self.network.bitcoin_id();
+        let inputs = explicit_total(self.prevouts.iter(), bitcoin_id);
+        let outputs = explicit_total(
+            self.outputs.iter().filter(|txout| !txout.is_fee()),
+            bitcoin_id,
+        );
+        let fee = explicit_total(
+            self.outputs.iter().filter(|txout| txout.is_fee()),
+            bitcoin_id,
+        );
+
+        if outputs.checked_add(fee) == Some(inputs) {
+            Ok(())
+        } else {
+            Err(Error::UnbalancedTransaction {
+                inputs,
+                outputs,
+                fee,
+            })
+        }
+    }
+
+    /// Return the size of the given signed transaction.
+    ///
+    /// Simplicity programs and their witness data make up most of the size of a spend,
+    /// but witness data counts only a quarter towards the weight.
+    pub fn size(tx: &elements::Transaction) -> TransactionSize {
+        let weight = tx.weight();
+        TransactionSize {
+            size: elements::encode::serialize(tx).len(),
+            weight,
+            vsize: weight.div_ceil(4),
+        }
+    }
+
     fn to_transaction(&self) -> elements::Transaction {
+        let mut inputs = self.inputs.clone();
+        // The lock time is ignored if all inputs are final
+        if self.lock_time != elements::LockTime::ZERO {
+            for input in &mut inputs {
+                if input.sequence == elements::Sequence::MAX {
+                    input.sequence = elements::Sequence::ENABLE_LOCKTIME_NO_RBF;
+                }
+            }
+        }
         elements::Transaction {
             version: 2,
-            lock_time: elements::LockTime::ZERO,
-            input: self.inputs.clone(),
+            lock_time: self.lock_time,
+            input: inputs,
             output: self.outputs.clone(),
         }
     }
 
-    pub fn sign(&self, state: &State) -> Option<elements::Transaction> {
+    pub fn sign(&self, state: &State) -> Result<elements::Transaction, Error> {
+        self.check_balance()?;
         let mut tx = self.to_transaction();
-        let cache = Rc::new(RefCell::new(simplicity::sighash::SighashCache::new(&tx)));
-        let mut witnesses = Vec::with_capacity(self.inputs.len());
+        let descriptors: Vec<_> = self.descriptors.iter().map(Some).collect();
+        sign_inputs(state, &mut tx, &self.prevouts, &descriptors)?;
+        Ok(tx)
+    }
+}
+
+/// Sign each input of the transaction that has a descriptor.
+///
+/// Inputs without descriptor are left as they are.
+fn sign_inputs(
+    state: &State,
+    tx: &mut elements::Transaction,
+    prevouts: &[elements::TxOut],
+    descriptors: &[Option<&Descriptor<PublicKey>>],
+) -> Result<(), Error> {
+    // The sighash cache borrows tx, so collect the witnesses before adding them to tx
+    let witnesses = {
+        let keypairs = state.get_all_keypairs();
+        let cache = Rc::new(RefCell::new(simplicity::sighash::SighashCache::new(&*tx)));
+        let mut witnesses = Vec::with_capacity(descriptors.len());
 
-        for (txin_index, descriptor) in self.descriptors.iter().enumerate() {
+        for (txin_index, descriptor) in descriptors.iter().enumerate() {
+            let descriptor = match descriptor {
+                Some(descriptor) => *descriptor,
+                None => continue,
+            };
             let satisfier = DynamicSigner {
                 state,
+                keypairs,
                 descriptor,
                 input_index: txin_index,
-                prevouts: elements::sighash::Prevouts::All(&self.prevouts),
+                prevouts: elements::sighash::Prevouts::All(prevouts),
                 locktime: tx.lock_time,
                 sequence: tx.input[txin_index].sequence,
                 cache: cache.clone(),
+                problems: RefCell::new(Vec::new()),
             };
 
-            let (script_witness, script_sig) = descriptor.get_satisfaction(satisfier).ok()?;
+            let (script_witness, script_sig) =
+                descriptor.get_satisfaction(&satisfier).map_err(|error| {
+                    // The error of miniscript doesn't say which part of the descriptor failed
+                    let mut problems = satisfier.problems.take();
+                    if problems.is_empty() {
+                        problems.push(error.to_string());
+                    }
+                    Error::CouldNotSatisfy {
+                        input: txin_index,
+                        problems,
+                    }
+                })?;
             assert!(
                 script_sig.is_empty(),
                 "No support for pre-segwit descriptors"
             );
-            witnesses.push(elements::TxInWitness {
-                amount_rangeproof: None,
-                inflation_keys_rangeproof: None,
-                script_witness,
-                pegin_witness: vec![],
-            });
+            witnesses.push((txin_index, script_witness));
         }
 
-        // In the first loop we could not mutate tx because it is borrowed by the sighash cache
-        // Add the witness to each input in a second loop
-        for (txin_index, witness) in witnesses.into_iter().enumerate() {
-            tx.input[txin_index].witness = witness;
-        }
+        witnesses
+    };
+
+    for (txin_index, script_witness) in witnesses {
+        tx.input[txin_index].witness.script_witness = script_witness;
+    }
+
+    Ok(())
+}
+
+/// Previous output of a transaction input, as given on the command line.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Prevout {
+    pub script_pubkey: elements::Script,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+    /// Bitcoin if omitted
+    #[serde(default)]
+    pub asset: Option<elements::AssetId>,
+}
 
-        Some(tx)
+/// Sign the inputs of an external transaction that spend coins of the wallet.
+///
+/// Explicit previous outputs of all inputs are required to compute the signature hash.
+/// Return the transaction and the number of signed inputs.
+pub fn sign_raw_transaction(
+    state: &State,
+    mut tx: elements::Transaction,
+    prevouts: &[Prevout],
+) -> Result<(elements::Transaction, usize), Error> {
+    if prevouts.len() != tx.input.len() {
+        return Err(Error::CouldNotParse(format!(
+            "expected {} prevouts, one for each input, but got {}",
+            tx.input.len(),
+            prevouts.len()
+        )));
     }
+    let bitcoin_id = state.network().bitcoin_id();
+    let prevouts: Vec<_> = prevouts
+        .iter()
+        .map(|prevout| elements::TxOut {
+            asset: elements::confidential::Asset::Explicit(prevout.asset.unwrap_or(bitcoin_id)),
+            value: elements::confidential::Value::Explicit(prevout.amount.to_sat()),
+            nonce: elements::confidential::Nonce::Null,
+            script_pubkey: prevout.script_pubkey.clone(),
+            witness: elements::TxOutWitness::default(),
+        })
+        .collect();
+
+    let wallet_descriptors = state.wallet_descriptors();
+    let descriptors: Vec<_> = prevouts
+        .iter()
+        .map(|prevout| {
+            wallet_descriptors
+                .iter()
+                .find(|d| d.script_pubkey() == prevout.script_pubkey)
+        })
+        .collect();
+    let signed = descriptors.iter().filter(|d| d.is_some()).count();
+
+    sign_inputs(state, &mut tx, &prevouts, &descriptors)?;
+    Ok((tx, signed))
+}
+
+/// Whether to sign with deterministic nonces instead of random auxiliary data.
+static DETERMINISTIC_NONCES: AtomicBool = AtomicBool::new(false);
+
+/// Sign with BIP 340 nonces that depend only on the key and the message.
+///
+/// The same transaction is then always signed the same way,
+/// which makes signatures reproducible for test vectors.
+pub fn use_deterministic_nonces() {
+    DETERMINISTIC_NONCES.store(true, Ordering::Relaxed);
 }
 
 struct DynamicSigner<'a, T, O>
@@ -231,6 +1988,8 @@ where
 {
     // Global state
     state: &'a State,
+    // Key pairs of the wallet, derived once for all inputs
+    keypairs: &'a HashMap<secp256k1_zkp::PublicKey, elements::schnorr::KeyPair>,
     // UTXO descriptor
     descriptor: &'a Descriptor<PublicKey>,
     // Transaction variables
@@ -240,6 +1999,8 @@ where
     sequence: elements::Sequence,
     // Use Rc<RefCell<_>> because Satisfier methods take &self while we need internal mutability
     cache: Rc<RefCell<simplicity::sighash::SighashCache<T>>>,
+    // Reasons why parts of the descriptor could not be satisfied, for error messages
+    problems: RefCell<Vec<String>>,
 }
 
 impl<'a, T, O> DynamicSigner<'a, T, O>
@@ -247,9 +2008,17 @@ where
     T: Deref<Target = elements::Transaction> + Clone,
     O: Borrow<elements::TxOut>,
 {
+    fn add_problem(&self, problem: String) {
+        self.problems.borrow_mut().push(problem);
+    }
+
     fn get_signature(sighash: &[u8], keypair: &elements::schnorr::KeyPair) -> elements::SchnorrSig {
         let msg = secp256k1_zkp::Message::from_slice(sighash).expect("32-byte sighash");
-        let sig = keypair.sign_schnorr(msg);
+        let sig = if DETERMINISTIC_NONCES.load(Ordering::Relaxed) {
+            secp256k1_zkp::SECP256K1.sign_schnorr_no_aux_rand(&msg, keypair)
+        } else {
+            keypair.sign_schnorr(msg)
+        };
 
         elements::SchnorrSig {
             sig,
@@ -265,10 +2034,12 @@ where
     O: Borrow<elements::TxOut>,
 {
     fn lookup_tap_key_spend_sig(&self) -> Option<elements::SchnorrSig> {
-        let internal_key = descriptor::get_control_block(self.descriptor)?
-            .internal_key
-            .to_public_key();
-        let keypair = self.state.get_keypair(&internal_key)?;
+        let internal_key = descriptor::get_internal_key(self.descriptor)?.to_public_key();
+        // Nobody knows the secret key of the unspendable key, so don't look for it
+        if internal_key.to_x_only_pubkey() == self.state.internal_key() {
+            return None;
+        }
+        let keypair = self.keypairs.get(&internal_key.inner)?;
         let sighash = self
             .cache
             .borrow_mut()
@@ -280,29 +2051,37 @@ where
             )
             .ok()?;
 
-        let signature = Self::get_signature(sighash.as_ref(), &keypair);
+        let signature = Self::get_signature(sighash.as_ref(), keypair);
         Some(signature)
     }
 
     fn lookup_tap_leaf_script_sig(
         &self,
         pk: &Pk,
-        _leaf_hash: &elements::taproot::TapLeafHash,
+        leaf_hash: &elements::taproot::TapLeafHash,
     ) -> Option<elements::SchnorrSig> {
-        let keypair = self.state.get_keypair(&pk.to_public_key())?;
+        let keypair = match self.keypairs.get(&pk.to_public_key().inner) {
+            Some(keypair) => keypair,
+            None => {
+                self.add_problem(format!("no secret key for public key {}", pk));
+                return None;
+            }
+        };
+        // The descriptor may have multiple leaves: sign for the leaf that is being satisfied
+        let cmr = descriptor::get_leaf_cmr(self.descriptor, leaf_hash)?;
         let sighash = self
             .cache
             .borrow_mut()
             .simplicity_spend_signature_hash(
                 self.input_index,
                 &self.prevouts,
-                descriptor::get_cmr(self.descriptor)?,
-                descriptor::get_control_block(self.descriptor)?,
+                cmr,
+                descriptor::get_control_block(self.descriptor, cmr)?,
                 self.state.network().genesis_hash(),
             )
             .ok()?;
 
-        let signature = Self::get_signature(sighash.as_ref(), &keypair);
+        let signature = Self::get_signature(sighash.as_ref(), keypair);
         Some(signature)
     }
 
@@ -311,17 +2090,37 @@ where
     }
 
     fn check_older(&self, sequence: elements::Sequence) -> bool {
-        Satisfier::<Pk>::check_older(&self.sequence, sequence)
+        let satisfied = Satisfier::<Pk>::check_older(&self.sequence, sequence);
+        if !satisfied {
+            self.add_problem(format!(
+                "sequence {} of the input doesn't satisfy the required {}",
+                self.sequence.to_consensus_u32(),
+                sequence.to_consensus_u32()
+            ));
+        }
+        satisfied
     }
 
     fn check_after(&self, locktime: elements::LockTime) -> bool {
-        Satisfier::<Pk>::check_after(&self.locktime, locktime)
+        let satisfied = Satisfier::<Pk>::check_after(&self.locktime, locktime);
+        if !satisfied {
+            self.add_problem(format!(
+                "lock time {} of the transaction doesn't satisfy the required {}",
+                self.locktime.to_consensus_u32(),
+                locktime.to_consensus_u32()
+            ));
+        }
+        satisfied
     }
 
     fn lookup_asm_program(
         &self,
         cmr: simplicity::Cmr,
     ) -> Option<Arc<simplicity::WitnessNode<simplicity::jet::Elements>>> {
-        self.state.assembly().get_satisfaction(&cmr)
+        let program = self.state.assembly().get_satisfaction(&cmr);
+        if program.is_none() {
+            self.add_problem(format!("no satisfaction for assembly fragment {}", cmr));
+        }
+        program
     }
 }
@@ -1,127 +1,421 @@
-use std::collections::HashMap;
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
 use std::fs::{File, OpenOptions};
+use std::io;
 use std::io::{BufReader, BufWriter};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use argon2::Argon2;
 use bitcoin::key::PublicKey;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use elements::secp256k1_zkp::rand::RngCore;
 use elements::{bitcoin, secp256k1_zkp};
 use elements_miniscript as miniscript;
-use elements_miniscript::TranslatePk;
-use miniscript::{elements, Descriptor, DescriptorPublicKey};
+use elements_miniscript::{ForEachKey, TranslatePk};
+use fs2::FileExt;
+use miniscript::elements::hex::{FromHex, ToHex};
+use miniscript::{elements, Descriptor, DescriptorPublicKey, MiniscriptKey, ToPublicKey};
 use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
+use crate::config::Config;
 use crate::descriptor;
 use crate::descriptor::AssemblySet;
 use crate::error::Error;
-use crate::key::{DescriptorSecretKey, ToEvenY};
-use crate::network::Network;
-use crate::rpc::Connection;
+use crate::key;
+use crate::key::{DescriptorSecretKey, ToEvenY, UnspendableKey};
+use crate::network::{CustomAddressParams, Network};
+use crate::rpc;
+use crate::rpc::{ChainTip, Connection};
+
+const DEFAULT_MAX_FEE_PERCENT: u64 = 10;
+const DEFAULT_SCAN_CACHE_TTL: u64 = 60;
+const DEFAULT_ACCOUNT: &str = "default";
+const BACKUP_VERSION: u32 = 1;
+const BACKUP_SALT_LEN: usize = 16;
+const BACKUP_NONCE_LEN: usize = 12;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct State {
     keymap: HashMap<DescriptorPublicKey, DescriptorSecretKey>,
-    descriptor: Descriptor<DescriptorPublicKey>,
-    next_index: u32,
+    #[serde(default)]
+    descriptors: Vec<NamedDescriptor>,
+    /// Index of the account that receives new addresses and change
+    #[serde(default)]
+    active_account: usize,
+    /// Single descriptor of state files from before accounts, moved into `descriptors` on load
+    #[serde(default, skip_serializing)]
+    descriptor: Option<Descriptor<DescriptorPublicKey>>,
+    #[serde(default, skip_serializing)]
+    next_index: Option<u32>,
     assembly: AssemblySet,
     #[serde(with = "bitcoin::amount::serde::as_sat")]
     fee: bitcoin::Amount,
     rpc: Connection,
     network: Network,
+    #[serde(default)]
+    last_scanned_height: Option<u64>,
+    /// Chain tip of the last full UTXO set scan, to detect reorgs
+    #[serde(default)]
+    last_tip: Option<ChainTip>,
+    /// Genesis block hash of the chain of the last tip,
+    /// which differs from the stored network under `--network`
+    #[serde(default)]
+    last_tip_genesis_hash: Option<elements::BlockHash>,
+    /// Dropped if it was written in an older format
+    #[serde(default, deserialize_with = "utxo_cache_or_none")]
+    utxo_cache: Option<UtxoCache>,
+    /// UTXOs that are excluded from balances and coin selection
+    #[serde(default)]
+    forgotten_utxos: BTreeSet<elements::OutPoint>,
+    /// Scalar r of the internal key H + rG, if the wallet doesn't use the NUMS point H itself.
+    #[serde(default)]
+    unspendable_secret: Option<secp256k1_zkp::SecretKey>,
+    /// SLIP 77 master blinding key for confidential addresses, derived from the key of the default account
+    #[serde(default)]
+    master_blinding_key: Option<secp256k1_zkp::SecretKey>,
+    /// Assets that were issued by this wallet
+    #[serde(default)]
+    issued_assets: Vec<IssuedAsset>,
+    #[serde(skip)]
+    child_descriptors: RefCell<Vec<Vec<Descriptor<PublicKey>>>>,
+    #[serde(skip)]
+    keypairs: OnceCell<HashMap<secp256k1_zkp::PublicKey, elements::schnorr::KeyPair>>,
+    #[serde(skip)]
+    overrides: Config,
+    /// Height of the chain tip of the scan that this process ran, if any
+    #[serde(skip)]
+    fresh_scan_height: Option<u64>,
+    /// Lock file that is held as long as the state is loaded
+    #[serde(skip)]
+    lock: Option<Rc<File>>,
 }
 
 impl State {
-    pub fn new(xpriv: DescriptorSecretKey) -> Self {
+    pub fn new(
+        xpriv: DescriptorSecretKey,
+        unspendable_secret: Option<secp256k1_zkp::SecretKey>,
+    ) -> Self {
         let xpub = xpriv.0.to_public(secp256k1_zkp::SECP256K1).expect("xpriv");
-        let descriptor = descriptor::simplicity_pk(xpub.clone());
+        let internal_key = match &unspendable_secret {
+            Some(r) => UnspendableKey::unspendable_with(r),
+            None => UnspendableKey::unspendable(),
+        };
+        let descriptor = descriptor::simplicity_pk(internal_key, xpub.clone());
+        let master_blinding_key = xpriv.master_blinding_key();
         let mut keymap = HashMap::new();
         keymap.insert(xpub, xpriv);
 
         Self {
             keymap,
-            descriptor,
-            next_index: 0,
+            descriptors: vec![NamedDescriptor::new(
+                DEFAULT_ACCOUNT.to_string(),
+                descriptor,
+            )],
+            active_account: 0,
+            descriptor: None,
+            next_index: None,
             assembly: AssemblySet::default(),
             fee: bitcoin::Amount::from_sat(1000),
             rpc: Connection::default(),
             network: Network::Regtest,
+            last_scanned_height: None,
+            last_tip: None,
+            last_tip_genesis_hash: None,
+            utxo_cache: None,
+            forgotten_utxos: BTreeSet::new(),
+            unspendable_secret,
+            master_blinding_key: Some(master_blinding_key),
+            issued_assets: Vec::new(),
+            child_descriptors: RefCell::new(Vec::new()),
+            keypairs: OnceCell::new(),
+            overrides: Config::default(),
+            fresh_scan_height: None,
+            lock: None,
         }
     }
 
-    fn next_index(&mut self) -> Result<u32, Error> {
-        if self.next_index & (1 << 31) == 0 {
-            let index = self.next_index;
-            self.next_index += 1;
-            Ok(index)
+    fn account(&self) -> &NamedDescriptor {
+        &self.descriptors[self.active_account]
+    }
+
+    fn account_mut(&mut self) -> &mut NamedDescriptor {
+        &mut self.descriptors[self.active_account]
+    }
+
+    /// Return the name of the active account.
+    pub fn account_name(&self) -> &str {
+        &self.account().name
+    }
+
+    /// Return the index of the next address of the active account.
+    pub fn account_next_index(&self) -> u32 {
+        self.account().next_index
+    }
+
+    /// Add an account with the given name and key, and make it the active account.
+    pub fn add_account(&mut self, name: String, xpriv: DescriptorSecretKey) -> Result<(), Error> {
+        if self.descriptors.iter().any(|account| account.name == name) {
+            return Err(Error::AccountExists(name));
+        }
+        let xpub = xpriv.0.to_public(secp256k1_zkp::SECP256K1).expect("xpriv");
+        let descriptor = descriptor::simplicity_pk(self.internal_key(), xpub.clone());
+        self.keymap.insert(xpub, xpriv);
+        self.descriptors
+            .push(NamedDescriptor::new(name, descriptor));
+        self.active_account = self.descriptors.len() - 1;
+        Ok(())
+    }
+
+    /// Make the account with the given name the active account.
+    pub fn set_account(&mut self, name: &str) -> Result<(), Error> {
+        self.active_account = self
+            .descriptors
+            .iter()
+            .position(|account| account.name == name)
+            .ok_or_else(|| Error::UnknownAccount(name.to_string()))?;
+        Ok(())
+    }
+
+    fn peek_index(&self) -> Result<u32, Error> {
+        let next_index = self.account().next_index;
+        if next_index & (1 << 31) == 0 {
+            Ok(next_index)
         } else {
-            Err(Error::Bip32(bitcoin::bip32::Error::InvalidChildNumber(
-                self.next_index,
-            )))
+            Err(Error::DerivationExhausted {
+                account: self.account_name().to_string(),
+                remaining: 0,
+            })
         }
     }
 
-    pub fn next_child_descriptor(&mut self) -> Result<Descriptor<PublicKey>, Error> {
-        let i = self.next_index()?;
-        Ok(self
-            .descriptor
-            .derived_descriptor(secp256k1_zkp::SECP256K1, i)
-            .expect("good xpub")
-            .translate_pk(&mut ToEvenY)
-            .expect("never fails"))
+    fn next_index(&mut self) -> Result<u32, Error> {
+        let index = self.peek_index()?;
+        self.account_mut().next_index += 1;
+        // Derive the key pairs again to include the new index
+        self.keypairs = OnceCell::new();
+        Ok(index)
     }
 
-    pub fn child_descriptors(&self) -> impl Iterator<Item = Descriptor<PublicKey>> + '_ {
-        (0..self.next_index).map(|i| {
-            self.descriptor
-                .derived_descriptor(secp256k1_zkp::SECP256K1, i)
-                .expect("good xpub")
-                .translate_pk(&mut ToEvenY)
-                .expect("never fails")
-        })
+    /// Return the number of addresses that were handed out so far, over all accounts.
+    pub fn address_count(&self) -> u32 {
+        self.descriptors
+            .iter()
+            .map(|account| account.next_index)
+            .sum()
     }
 
-    pub fn get_keypair(&self, key: &PublicKey) -> Option<elements::schnorr::KeyPair> {
-        for parent_sk in self.keymap.values() {
-            // TODO: Update once there is support for multiple descriptors
-            for index in 0..self.next_index {
-                let child_sk = parent_sk
-                    .clone()
-                    .at_derivation_index(index)
-                    .ok()?
-                    .to_private_key()
-                    .inner;
-                if child_sk.public_key(secp256k1_zkp::SECP256K1) == key.inner {
-                    let keypair = elements::schnorr::KeyPair::from_secret_key(
-                        secp256k1_zkp::SECP256K1,
-                        &child_sk,
-                    );
-                    return Some(keypair);
-                }
-                // Case where public key P with odd y-coordinate was converted
-                // into public key -P with even y-coordinate:
-                // P = xG and -P = (-x)G for the generator G
-                if child_sk.negate().public_key(secp256k1_zkp::SECP256K1) == key.inner {
-                    let keypair = elements::schnorr::KeyPair::from_secret_key(
-                        secp256k1_zkp::SECP256K1,
-                        &child_sk.negate(),
-                    );
-                    return Some(keypair);
+    /// Mark the index returned by [`State::peek_child_descriptor`] as used.
+    pub fn advance_index(&mut self) -> Result<(), Error> {
+        self.next_index().map(|_| ())
+    }
+
+    fn child_descriptor(&self, index: u32) -> Descriptor<PublicKey> {
+        self.account().child_descriptor(index)
+    }
+
+    /// Return the child descriptor at the next unused index without advancing the index.
+    ///
+    /// Call [`State::advance_index`] once the descriptor has actually been used.
+    pub fn peek_child_descriptor(&self) -> Result<Descriptor<PublicKey>, Error> {
+        let i = self.peek_index()?;
+        Ok(self.child_descriptor(i))
+    }
+
+    /// Advance the index past child descriptors that already hold coins.
+    ///
+    /// This happens when the same keys are used from another state file,
+    /// or when an old backup of the state file is restored.
+    /// Only unspent coins are detected, because the wallet scans the UTXO set.
+    /// The next index is part of the cached scan, see [`State::wallet_descriptors`],
+    /// so this costs another scan only after an index has been skipped.
+    ///
+    /// Return the skipped indices.
+    pub fn skip_used_indices(&mut self) -> Result<Vec<u32>, Error> {
+        let mut skipped = Vec::new();
+
+        loop {
+            let index = self.peek_index()?;
+            let descriptor = self.child_descriptor(index);
+            self.refresh_utxo_cache()?;
+            let cache = self.utxo_cache.as_ref().expect("cache is fresh");
+            // Forgotten coins count, too: the address did receive them
+            if !cache.utxos.0.iter().any(|u| u.descriptor == descriptor) {
+                return Ok(skipped);
+            }
+            skipped.push(index);
+            self.advance_index()?;
+        }
+    }
+
+    /// Return the child descriptors at all used indices of all accounts.
+    ///
+    /// Descriptors are derived once and reused afterwards.
+    pub fn child_descriptors(&self) -> Vec<Descriptor<PublicKey>> {
+        let mut cache = self.child_descriptors.borrow_mut();
+        cache.resize(self.descriptors.len(), Vec::new());
+        let mut descriptors = Vec::new();
+
+        for (account, cache) in self.descriptors.iter().zip(cache.iter_mut()) {
+            for i in cache.len() as u32..account.next_index {
+                cache.push(account.child_descriptor(i));
+            }
+            descriptors.extend_from_slice(&cache[..account.next_index as usize]);
+        }
+
+        descriptors
+    }
+
+    /// Return the used child descriptors of all accounts
+    /// together with the name of their account and their derivation index.
+    pub fn child_descriptor_origins(&self) -> Vec<(&str, u32, Descriptor<PublicKey>)> {
+        self.descriptors
+            .iter()
+            .flat_map(|account| {
+                (0..account.next_index).map(move |index| (account.name.as_str(), index))
+            })
+            .zip(self.child_descriptors())
+            .map(|((name, index), descriptor)| (name, index, descriptor))
+            .collect()
+    }
+
+    /// Derive the key pairs of all used child keys, indexed by their public key.
+    fn derive_keypairs(&self) -> HashMap<secp256k1_zkp::PublicKey, elements::schnorr::KeyPair> {
+        let mut keypairs = HashMap::new();
+
+        for account in &self.descriptors {
+            let parent_sks = self
+                .keymap
+                .iter()
+                .filter(|(xpub, _)| account.contains_key(xpub))
+                .map(|(_, xpriv)| xpriv);
+
+            for parent_sk in parent_sks {
+                for index in 0..account.next_index {
+                    let child_sk = match parent_sk
+                        .at_derivation_index(index)
+                        .and_then(|child_sk| child_sk.to_private_key())
+                    {
+                        Ok(child_sk) => child_sk.inner,
+                        Err(..) => continue,
+                    };
+                    // Also cover the case where public key P with odd y-coordinate was converted
+                    // into public key -P with even y-coordinate:
+                    // P = xG and -P = (-x)G for the generator G
+                    for mut sk in [child_sk, child_sk.negate()] {
+                        let keypair = elements::schnorr::KeyPair::from_secret_key(
+                            secp256k1_zkp::SECP256K1,
+                            &sk,
+                        );
+                        keypairs.insert(sk.public_key(secp256k1_zkp::SECP256K1), keypair);
+                        // Only the key pair needs to stay in memory
+                        sk.non_secure_erase();
+                    }
                 }
             }
         }
 
-        None
+        keypairs
+    }
+
+    /// Return the key pairs of all used child keys, indexed by their public key.
+    ///
+    /// The key pairs are derived on the first call and reused afterwards,
+    /// so signing many inputs costs a single derivation pass.
+    pub fn get_all_keypairs(
+        &self,
+    ) -> &HashMap<secp256k1_zkp::PublicKey, elements::schnorr::KeyPair> {
+        self.keypairs.get_or_init(|| self.derive_keypairs())
     }
 
-    pub fn next_address(&mut self) -> Result<elements::Address, Error> {
-        let index = self.next_index()?;
+    pub fn next_address(&mut self) -> Result<AddressInfo, Error> {
+        let mut addresses = self.next_addresses(1)?;
+        Ok(addresses.pop().expect("one address"))
+    }
+
+    /// Return the address at the given index of the active account,
+    /// without marking the index as used.
+    pub fn address_at(&self, index: u32) -> Result<AddressInfo, Error> {
+        if 1 << 31 <= index {
+            return Err(Error::CouldNotParse(format!(
+                "index {} is hardened, but only non-hardened indices are derived",
+                index
+            )));
+        }
         let child = self
+            .account()
             .descriptor
             .at_derivation_index(index)
             .expect("valid child index");
-        let address = child
-            .address(self.network.address_params())
-            .expect("taproot address");
-        Ok(address)
+        Ok(AddressInfo::new(
+            Some(index),
+            &child,
+            self.network().address_params(),
+        ))
+    }
+
+    /// Return the addresses at the next `count` unused indices.
+    ///
+    /// The index is advanced by `count` at once.
+    pub fn next_addresses(&mut self, count: u32) -> Result<Vec<AddressInfo>, Error> {
+        let first = self.peek_index()?;
+        let end = first
+            .checked_add(count)
+            .filter(|end| *end <= 1 << 31)
+            .ok_or_else(|| Error::DerivationExhausted {
+                account: self.account_name().to_string(),
+                remaining: (1 << 31) - first,
+            })?;
+        self.account_mut().next_index = end;
+        // Derive the key pairs again to include the new indices
+        self.keypairs = OnceCell::new();
+
+        let params = self.network().address_params();
+        let addresses = (first..end)
+            .map(|index| {
+                let child = self
+                    .account()
+                    .descriptor
+                    .at_derivation_index(index)
+                    .expect("valid child index");
+                AddressInfo::new(Some(index), &child, params)
+            })
+            .collect();
+        Ok(addresses)
+    }
+
+    /// Return the master blinding key, deriving it if the wallet was created without one.
+    fn master_blinding_key(&mut self) -> secp256k1_zkp::SecretKey {
+        if let Some(master_blinding_key) = self.master_blinding_key {
+            return master_blinding_key;
+        }
+        let default_account = &self.descriptors[0];
+        let master_blinding_key = self
+            .keymap
+            .iter()
+            .find(|(xpub, _)| default_account.contains_key(xpub))
+            .map(|(_, xpriv)| xpriv.master_blinding_key())
+            .expect("default account has a key");
+        self.master_blinding_key = Some(master_blinding_key);
+        master_blinding_key
+    }
+
+    /// Turn the address into a confidential address with the blinding key of its script pubkey.
+    ///
+    /// Not offered yet, because scanning cannot unblind the coins that such an address receives.
+    #[allow(dead_code)]
+    pub fn blind(&mut self, mut info: AddressInfo) -> AddressInfo {
+        let master_blinding_key = self.master_blinding_key();
+        let blinding_key = key::blinding_private_key(&master_blinding_key, &info.script_pubkey);
+        info.address = info
+            .address
+            .to_confidential(blinding_key.public_key(secp256k1_zkp::SECP256K1));
+        info.blinding_key = Some(blinding_key);
+        info
     }
 
     pub fn assembly(&self) -> &AssemblySet {
@@ -132,8 +426,36 @@ impl State {
         &mut self.assembly
     }
 
+    /// Return the internal key of the taproot outputs of this wallet.
+    pub fn internal_key<Pk: UnspendableKey>(&self) -> Pk {
+        match &self.unspendable_secret {
+            Some(r) => Pk::unspendable_with(r),
+            None => Pk::unspendable(),
+        }
+    }
+
+    /// Import the assembly fragment with the given CMR.
+    ///
+    /// Return `false` if the fragment was already imported.
+    pub fn import_assembly(&mut self, cmr: simplicity::Cmr) -> bool {
+        let internal_key = self.internal_key();
+        self.assembly.insert(cmr, internal_key)
+    }
+
     pub fn fee(&self) -> bitcoin::Amount {
-        self.fee
+        self.overrides.fee.unwrap_or(self.fee)
+    }
+
+    /// Maximum fee as a percentage of the amount sent, unless forced otherwise.
+    pub fn max_fee_percent(&self) -> u64 {
+        self.overrides
+            .max_fee_percent
+            .unwrap_or(DEFAULT_MAX_FEE_PERCENT)
+    }
+
+    /// Use the given fee for this command, without saving it.
+    pub fn raise_fee(&mut self, fee: bitcoin::Amount) {
+        self.overrides.fee = Some(fee);
     }
 
     pub fn set_fee(&mut self, fee: bitcoin::Amount) {
@@ -141,46 +463,584 @@ impl State {
     }
 
     pub fn rpc(&self) -> &Connection {
-        &self.rpc
+        self.overrides.rpc.as_ref().unwrap_or(&self.rpc)
     }
 
     pub fn set_rpc(&mut self, rpc: Connection) {
         self.rpc = rpc;
     }
 
+    pub fn scan_batch_size(&self) -> usize {
+        self.overrides
+            .scan_batch_size
+            .unwrap_or(rpc::DEFAULT_SCAN_BATCH_SIZE)
+    }
+
     pub fn network(&self) -> Network {
-        self.network
+        let network = self.overrides.network.unwrap_or(self.network);
+        match self.overrides.address_params {
+            Some(address_params) => network.with_address_params(address_params),
+            None => network,
+        }
     }
 
     pub fn set_network(&mut self, network: Network) {
         self.network = network;
     }
 
+    pub fn last_scanned_height(&self) -> Option<u64> {
+        self.last_scanned_height
+    }
+
+    pub fn set_last_scanned_height(&mut self, height: u64) {
+        self.last_scanned_height = Some(height);
+    }
+
+    /// Return the height of the chain tip that the UTXO set was scanned at by this process.
+    ///
+    /// Return `None` if the UTXOs came from the cache of an earlier process.
+    pub fn fresh_scan_height(&self) -> Option<u64> {
+        self.fresh_scan_height
+    }
+
+    /// Number of seconds for which scan results are reused.
+    pub fn scan_cache_ttl(&self) -> u64 {
+        self.overrides
+            .scan_cache_ttl
+            .unwrap_or(DEFAULT_SCAN_CACHE_TTL)
+    }
+
+    /// Return the descriptors of all coins of the wallet, spendable or locked.
+    ///
+    /// This includes the next unused index of the active account,
+    /// so the scan also finds coins that its address already received.
+    pub fn wallet_descriptors(&self) -> Vec<Descriptor<PublicKey>> {
+        let mut descriptors = self.child_descriptors();
+        if let Ok(next) = self.peek_child_descriptor() {
+            descriptors.push(next);
+        }
+        descriptors.extend(self.assembly.descriptors().cloned());
+        descriptors
+    }
+
+    /// Return the bitcoin UTXOs of the given wallet descriptors.
+    ///
+    /// See [`State::scan_asset`].
+    pub fn scan(&mut self, descriptors: &[Descriptor<PublicKey>]) -> Result<UtxoSet, Error> {
+        let bitcoin_id = self.network().bitcoin_id();
+        self.scan_asset(descriptors, bitcoin_id)
+    }
+
+    /// Return the UTXOs of the given asset and the given wallet descriptors.
+    ///
+    /// The UTXO set is scanned for all wallet descriptors at once.
+    /// The results are cached and reused until they are older than [`State::scan_cache_ttl`]
+    /// or until the wallet gains new descriptors.
+    pub fn scan_asset(
+        &mut self,
+        descriptors: &[Descriptor<PublicKey>],
+        asset: elements::AssetId,
+    ) -> Result<UtxoSet, Error> {
+        self.refresh_utxo_cache()?;
+
+        let cache = self.utxo_cache.as_ref().expect("cache is fresh");
+        let utxos = cache
+            .utxos
+            .0
+            .iter()
+            .filter(|u| u.asset == asset && descriptors.contains(&u.descriptor))
+            .filter(|u| !self.forgotten_utxos.contains(&u.outpoint))
+            .cloned()
+            .collect();
+        Ok(UtxoSet(utxos))
+    }
+
+    /// Scan the UTXO set for all wallet descriptors, unless the cached results are still fresh.
+    fn refresh_utxo_cache(&mut self) -> Result<(), Error> {
+        let wallet_descriptors = self.wallet_descriptors();
+        let genesis_hash = self.network().genesis_hash();
+        let now = unix_time();
+        // Results of another chain, such as under `--network`, are never reused
+        let is_fresh = self.utxo_cache.as_ref().is_some_and(|cache| {
+            cache.genesis_hash == genesis_hash
+                && cache.descriptors == wallet_descriptors
+                && now < cache.timestamp.saturating_add(self.scan_cache_ttl())
+        });
+
+        if !is_fresh {
+            let (utxos, tip) = self.rpc().scan(
+                &wallet_descriptors,
+                self.scan_batch_size(),
+                self.network().bitcoin_id(),
+            )?;
+            if let Some(tip) = tip {
+                self.check_reorg(tip)?;
+                self.last_tip = Some(tip);
+                self.last_tip_genesis_hash = Some(genesis_hash);
+                self.fresh_scan_height = Some(tip.height);
+            }
+            self.utxo_cache = Some(UtxoCache {
+                genesis_hash,
+                timestamp: now,
+                descriptors: wallet_descriptors,
+                utxos,
+            });
+        }
+        Ok(())
+    }
+
+    /// Warn if the block of the previous scan is no longer part of the best chain.
+    ///
+    /// Coins that were confirmed in the replaced blocks may be gone,
+    /// so balances from before the reorg cannot be trusted.
+    ///
+    /// A tip of another chain, such as under `--network`, is not compared.
+    fn check_reorg(&self, tip: ChainTip) -> Result<(), Error> {
+        if self.last_tip_genesis_hash != Some(self.network().genesis_hash()) {
+            return Ok(());
+        }
+        let last = match self.last_tip {
+            Some(last) if last != tip => last,
+            _ => return Ok(()),
+        };
+        let reorged =
+            tip.height < last.height || self.rpc().getblockhash(last.height)? != last.hash;
+        if reorged {
+            eprintln!(
+                "Warning: block {} at height {} of the previous scan is no longer in the best chain; previously confirmed balances may have changed",
+                last.hash, last.height
+            );
+        }
+        Ok(())
+    }
+
+    /// Exclude the UTXO from balances and coin selection.
+    ///
+    /// Return `false` if the UTXO was already forgotten.
+    pub fn forget_utxo(&mut self, outpoint: elements::OutPoint) -> bool {
+        self.forgotten_utxos.insert(outpoint)
+    }
+
+    /// Include a forgotten UTXO in balances and coin selection again.
+    ///
+    /// Return `false` if the UTXO was not forgotten.
+    pub fn remember_utxo(&mut self, outpoint: &elements::OutPoint) -> bool {
+        self.forgotten_utxos.remove(outpoint)
+    }
+
+    pub fn issued_assets(&self) -> &[IssuedAsset] {
+        &self.issued_assets
+    }
+
+    pub fn add_issued_asset(&mut self, asset: IssuedAsset) {
+        self.issued_assets.push(asset);
+    }
+
+    /// Forget the cached scan results, for instance because coins were spent.
+    pub fn clear_utxo_cache(&mut self) {
+        self.utxo_cache = None;
+    }
+
+    /// Use the given settings instead of the stored ones, without persisting them.
+    pub fn set_overrides(&mut self, overrides: Config) {
+        self.overrides = overrides;
+    }
+
+    /// Move the single descriptor of old state files into the first account.
+    fn migrate(&mut self) {
+        if let Some(descriptor) = self.descriptor.take() {
+            let mut account = NamedDescriptor::new(DEFAULT_ACCOUNT.to_string(), descriptor);
+            account.next_index = self.next_index.take().unwrap_or(0);
+            self.descriptors.insert(0, account);
+        }
+    }
+
+    /// Check that the secret keys in the keymap belong to the keys of the descriptors.
+    ///
+    /// Otherwise, signing would fail much later with a less helpful error.
+    fn check_keymap(&self) -> Result<(), Error> {
+        match self.keymap_problems().into_iter().next() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Return all problems with the keymap, see [`State::check_keymap`].
+    fn keymap_problems(&self) -> Vec<Error> {
+        let mut problems = Vec::new();
+        if self.keymap.is_empty() {
+            problems.push(Error::InconsistentWallet("keymap is empty".to_string()));
+        }
+        if self.descriptors.len() <= self.active_account {
+            problems.push(Error::InconsistentWallet(format!(
+                "active account {} does not exist",
+                self.active_account
+            )));
+        }
+
+        for (xpub, xpriv) in &self.keymap {
+            if xpriv.is_multipath() {
+                problems.push(Error::UnsupportedKey(format!(
+                    "{} has multiple derivation paths",
+                    xpub
+                )));
+                continue;
+            }
+            match xpriv.0.to_public(secp256k1_zkp::SECP256K1) {
+                Ok(derived) if &derived != xpub => {
+                    problems.push(Error::InconsistentWallet(format!(
+                        "secret key belongs to {}, but is stored for {}",
+                        derived, xpub
+                    )))
+                }
+                Ok(..) => {}
+                Err(e) => problems.push(Error::InconsistentWallet(format!(
+                    "secret key for {} is invalid: {}",
+                    xpub, e
+                ))),
+            }
+            if !self.descriptors.iter().any(|a| a.contains_key(xpub)) {
+                problems.push(Error::InconsistentWallet(format!(
+                    "no descriptor contains key {}",
+                    xpub
+                )));
+            }
+        }
+
+        problems
+    }
+
+    /// Check the whole state and return every problem that was found.
+    ///
+    /// Unlike [`State::load`], this doesn't stop at the first problem.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<_> = self
+            .keymap_problems()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        problems.extend(self.assembly.problems());
+
+        if self.fee == bitcoin::Amount::ZERO {
+            problems.push("stored fee is zero, so the node would reject transactions".to_string());
+        }
+        if bitcoin::Amount::ONE_BTC < self.fee {
+            problems.push(format!("stored fee of {} is unreasonably high", self.fee));
+        }
+        if let Network::Custom { address_params, .. } = self.network {
+            if let Err(error) = CustomAddressParams::from(address_params).validate() {
+                problems.push(format!("address parameters are invalid: {}", error));
+            }
+        }
+
+        problems
+    }
+
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let file = File::open(path)?;
+        let state = Self::load_unchecked(path)?;
+        state.check_keymap()?;
+        Ok(state)
+    }
+
+    /// Load the state file without checking the keys, see [`State::problems`].
+    pub fn load_unchecked<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(Error::NoWallet);
+        }
+        // Lock before opening: saving replaces the state file
+        let lock = lock_wallet(path)?;
+        let file = File::open(path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => Error::NoWallet,
+            _ => Error::IO(e),
+        })?;
         let reader = BufReader::new(file);
-        let state = serde_json::from_reader(reader)?;
+        let mut state: Self = serde_json::from_reader(reader)?;
+        state.migrate();
+        state.lock = Some(Rc::new(lock));
+        Ok(state)
+    }
+
+    /// Write a copy of the wallet to a new file at the given path,
+    /// including the keys of all accounts and the assembly fragments with their satisfactions.
+    ///
+    /// The copy is encrypted with the given passphrase.
+    /// Caches and the RPC password are left out. Restore the copy with [`State::import_backup`].
+    pub fn export_backup<P: AsRef<Path>>(&self, path: P, passphrase: &str) -> Result<(), Error> {
+        let mut state = self.clone();
+        state.clear_utxo_cache();
+        state.last_tip = None;
+        state.last_tip_genesis_hash = None;
+        // The RPC password belongs to the machine, not to the wallet
+        state.rpc.pass = None;
+
+        let mut salt = [0u8; BACKUP_SALT_LEN];
+        let mut nonce = [0u8; BACKUP_NONCE_LEN];
+        secp256k1_zkp::rand::rngs::OsRng.fill_bytes(&mut salt);
+        secp256k1_zkp::rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let mut plaintext = serde_json::to_vec(&state)?;
+        let ciphertext = backup_cipher(passphrase, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .expect("state fits into one ChaCha20-Poly1305 message");
+        plaintext.zeroize();
+        let backup = Backup {
+            version: BACKUP_VERSION,
+            salt: salt.to_hex(),
+            nonce: nonce.to_hex(),
+            ciphertext: ciphertext.to_hex(),
+        };
+
+        // Fail if there already is a file
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, &backup)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Read a copy of a wallet that was written by [`State::export_backup`]
+    /// and decrypt it with the given passphrase.
+    pub fn import_backup<P: AsRef<Path>>(path: P, passphrase: &str) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(path)?);
+        let backup: Backup = serde_json::from_reader(reader)?;
+        if backup.version != BACKUP_VERSION {
+            return Err(Error::CouldNotParse(format!(
+                "unsupported backup version {}",
+                backup.version
+            )));
+        }
+        let decode =
+            |hex: &str| Vec::<u8>::from_hex(hex).map_err(|e| Error::CouldNotParse(e.to_string()));
+        let salt = decode(&backup.salt)?;
+        let nonce = decode(&backup.nonce)?;
+        if nonce.len() != BACKUP_NONCE_LEN {
+            return Err(Error::CouldNotParse(format!(
+                "backup nonce has {} bytes instead of {}",
+                nonce.len(),
+                BACKUP_NONCE_LEN
+            )));
+        }
+        let mut plaintext = backup_cipher(passphrase, &salt)?
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                decode(&backup.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| Error::WrongPassphrase)?;
+        let state = serde_json::from_slice::<State>(&plaintext);
+        plaintext.zeroize();
+
+        let mut state = state?;
+        state.migrate();
+        state.check_keymap()?;
         Ok(state)
     }
 
     pub fn save<P: AsRef<Path>>(&self, path: P, init: bool) -> Result<(), Error> {
+        let path = path.as_ref();
+
+        // Write to a temporary file and move it to the state file,
+        // so a crash during serialization never corrupts the existing state
+        let tmp_path = sibling_path(path, ".tmp")?;
+
         let file = OpenOptions::new()
             .write(true)
             .truncate(true)
-            .create_new(init)
-            .open(path)?;
-        let writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(writer, self)?;
+            .create(true)
+            .open(&tmp_path)?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        if init {
+            // Unlike a rename, a hard link fails if there already is a state file
+            let linked = std::fs::hard_link(&tmp_path, path);
+            std::fs::remove_file(&tmp_path)?;
+            linked?;
+        } else {
+            std::fs::rename(&tmp_path, path)?;
+        }
         Ok(())
     }
 }
 
-#[derive(Clone, Debug)]
+/// Portable copy of a wallet, encrypted with a passphrase.
+#[derive(Serialize, Deserialize)]
+struct Backup {
+    version: u32,
+    /// Salt of the Argon2id key derivation in hex
+    salt: String,
+    /// ChaCha20-Poly1305 nonce in hex
+    nonce: String,
+    /// Encrypted JSON of the state in hex
+    ciphertext: String,
+}
+
+/// Derive the cipher of a backup from the passphrase with Argon2id.
+fn backup_cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305, Error> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::CouldNotParse(e.to_string()))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+    key.zeroize();
+    Ok(cipher)
+}
+
+/// Return the path of a file next to the given file, with the given suffix appended to its name.
+fn sibling_path(path: &Path, suffix: &str) -> Result<PathBuf, io::Error> {
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?
+        .to_owned();
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+/// Take the advisory lock of the state file at the given path,
+/// so two commands never load and save the same wallet at once.
+///
+/// The lock is released when the returned file is closed, also if the process crashes.
+fn lock_wallet(path: &Path) -> Result<File, Error> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(sibling_path(path, ".lock")?)?;
+    file.try_lock_exclusive().map_err(|e| {
+        if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+            Error::WalletLocked
+        } else {
+            Error::IO(e)
+        }
+    })?;
+    Ok(file)
+}
+
+/// Descriptor of an account, which has its own chain of addresses.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NamedDescriptor {
+    pub name: String,
+    pub descriptor: Descriptor<DescriptorPublicKey>,
+    next_index: u32,
+}
+
+impl NamedDescriptor {
+    pub fn new(name: String, descriptor: Descriptor<DescriptorPublicKey>) -> Self {
+        Self {
+            name,
+            descriptor,
+            next_index: 0,
+        }
+    }
+
+    fn child_descriptor(&self, index: u32) -> Descriptor<PublicKey> {
+        self.descriptor
+            .derived_descriptor(secp256k1_zkp::SECP256K1, index)
+            .expect("good xpub")
+            .translate_pk(&mut ToEvenY)
+            .expect("never fails")
+    }
+
+    fn contains_key(&self, key: &DescriptorPublicKey) -> bool {
+        // for_each_key returns true if the predicate holds for all keys
+        !self.descriptor.for_each_key(|pk| pk != key)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AddressInfo {
+    pub address: elements::Address,
+    pub index: Option<u32>,
+    pub descriptor: String,
+    pub script_pubkey: elements::Script,
+    /// Private blinding key, if the address is confidential
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blinding_key: Option<secp256k1_zkp::SecretKey>,
+}
+
+impl AddressInfo {
+    pub fn new<Pk: MiniscriptKey + ToPublicKey>(
+        index: Option<u32>,
+        descriptor: &Descriptor<Pk>,
+        params: &'static elements::AddressParams,
+    ) -> Self {
+        let address = descriptor.address(params).expect("taproot address");
+        Self {
+            script_pubkey: address.script_pubkey(),
+            address,
+            index,
+            descriptor: descriptor.to_string(),
+            blinding_key: None,
+        }
+    }
+}
+
+impl fmt::Display for AddressInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.address)?;
+        if let Some(index) = self.index {
+            writeln!(f, "Index:         {}", index)?;
+        }
+        writeln!(f, "Descriptor:    {}", self.descriptor)?;
+        if let Some(blinding_key) = &self.blinding_key {
+            writeln!(f, "Blinding key:  {}", blinding_key.display_secret())?;
+        }
+        write!(
+            f,
+            "Script pubkey: {}",
+            self.script_pubkey.as_bytes().to_hex()
+        )
+    }
+}
+
+/// Asset that was issued by this wallet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IssuedAsset {
+    pub asset: elements::AssetId,
+    /// Token that allows reissuing the asset
+    pub token: Option<elements::AssetId>,
+    pub txid: elements::Txid,
+}
+
+/// Deserialize the UTXO cache, or drop it if it doesn't match the current format.
+///
+/// The cache can always be recreated by scanning the UTXO set again.
+fn utxo_cache_or_none<'de, D>(deserializer: D) -> Result<Option<UtxoCache>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = serde_json::Value::deserialize(deserializer)?;
+    Ok(serde_json::from_value(value).ok())
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct UtxoCache {
+    /// Genesis block hash of the scanned chain
+    genesis_hash: elements::BlockHash,
+    /// Unix time of the scan in seconds
+    timestamp: u64,
+    descriptors: Vec<Descriptor<PublicKey>>,
+    utxos: UtxoSet,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Utxo {
     pub descriptor: Descriptor<PublicKey>,
+    /// Amount in satoshi, or in the smallest unit of other assets
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
     pub amount: bitcoin::amount::Amount,
+    pub asset: elements::AssetId,
     pub outpoint: elements::OutPoint,
+    /// Height of the block that confirmed the UTXO
+    pub height: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UtxoSet(pub Vec<Utxo>);